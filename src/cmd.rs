@@ -1 +1,5 @@
-pub mod convert;
\ No newline at end of file
+pub mod convert;
+pub mod inspect;
+pub mod merge_v8;
+pub mod overlay;
+pub mod serve;
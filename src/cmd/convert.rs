@@ -1,21 +1,32 @@
+use crate::css::{build_css_source_maps, collect_css_coverage, handle_css_coverage};
 use crate::format::istanbul;
 use crate::format::istanbul::IstanbulCov;
+use crate::format::layout::{OutputLayout, ProcessInfo};
+use crate::format::line::{fill_legacy_lines, LineMode};
+use crate::format::report::{write_reports, ReportOptions, Reporter};
 use crate::format::script_coverage::{
-    build_coverage_range_tree, collect_coverage_helper, find_root_value_only, read_only,
-    CoverRangeNode, CoverageRange, ScriptCoverage,
+    collect_coverage_file, collect_coverage_helper, coverage_tree, mapping_count, ModuleWrapper,
+    ScriptCoverage,
 };
-use crate::fputil::path_to_abs;
+use crate::format::v8_merge::merge_script_coverages;
+use crate::format::MappingItem;
+use crate::fputil::{glob_abs, is_legal_source_path, path_to_abs, safe_join};
+use crate::statement::cache::StatementCache;
 use crate::statement::{build_statements_from_local, Statement};
 use crate::timer::Timer;
+use crate::translate::{function_link, LinkOptions};
+use crate::traverse::parse_code;
 use anyhow::{anyhow, Result};
 use clap::Args;
+use percent_encoding::percent_decode_str;
 use rayon::prelude::*;
 use regex::Regex;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use tokio::fs;
-use tracing::{error, info, instrument, trace, warn};
+use tracing::{debug, error, info, instrument, trace, warn};
+use url::Url;
 
 #[derive(Args)]
 pub struct ConvertArgs {
@@ -31,6 +42,46 @@ pub struct ConvertArgs {
     source_map_base: String, // 本地 source map 文件所在的根目录
     #[arg(long)]
     source_relocate: Option<String>, // 用来替换 source map 里面 sources 的路径
+    #[arg(long)]
+    generated_coverage: bool, // 没有 source map 的脚本直接统计生成代码的覆盖率
+    #[arg(long)]
+    include_ignored: bool, // 不使用 source map 里面的 ignoreList 过滤第三方代码
+    #[arg(long, value_enum, default_value_t = LineMode::Max)]
+    line_mode: LineMode, // 同一行多个语句的执行次数不同时，行覆盖率取最小值还是最大值
+    #[arg(long)]
+    emit_lines: bool, // 在 istanbul 报告里面输出 `l` 字段
+    #[arg(long, value_enum)]
+    reporter: Vec<Reporter>, // 额外输出的报告格式，可以指定多个
+    #[arg(long)]
+    skip_full: bool, // text 报告不显示 100% 覆盖的文件
+    #[arg(long, default_value = ".")]
+    project_root: String, // 报告里面的文件路径相对这个目录输出，sonar 需要和扫描的目录一致
+    #[arg(long, default_value = ".")]
+    report_dir: String, // sonar / clover 等报告的输出目录，相对 output
+    #[arg(long, default_value = ".nyc_output")]
+    temp_dir: String, // 每个测试的覆盖率和 processinfo 的输出目录，相对 output
+    #[arg(long, default_value = ".nyc_output/merged.json")]
+    output_file: String, // 合并之后的覆盖率文件，相对 output，比如 coverage-final.json
+    #[arg(long)]
+    compact: bool, // 输出不带缩进的 JSON
+    #[arg(long)]
+    per_test: bool, // 每个测试文件单独输出一份 istanbul 覆盖率，兼容 nyc 的 merge
+    #[arg(long)]
+    materialize_sources: bool, // 把 source map 里面的 sourcesContent 写到输出目录，方便在其他机器上生成报告
+    #[arg(long)]
+    css_pattern: Option<String>, // playwright / puppeteer 输出的 css 覆盖率文件
+    #[arg(long, default_value = "auto")]
+    module_wrapper: ModuleWrapper, // auto、esm、cjs 或者自定义加载器在源码前面加的字符数
+    #[arg(long)]
+    no_v8_merge: bool, // 不在映射之前合并多个测试的 v8 区间，每个测试单独转换
+    #[arg(long)]
+    watch: bool, // 转换完成之后继续监听 pattern，新的覆盖率文件增量合并到结果里面
+    #[arg(long, default_value_t = 1000)]
+    watch_interval: u64, // 监听模式扫描覆盖率文件的间隔，单位毫秒
+    #[arg(long)]
+    cache_dir: Option<String>, // source map 预处理结果的缓存目录，相对 project_root，不指定的时候项目里面有 node_modules 就用 node_modules/.cache，否则用系统缓存目录
+    #[arg(long)]
+    no_cache: bool, // 不读也不写预处理缓存
 }
 #[instrument(skip(args))]
 pub async fn exec(args: &ConvertArgs) -> Result<()> {
@@ -48,20 +99,62 @@ pub async fn exec(args: &ConvertArgs) -> Result<()> {
         })
         .flatten();
 
-    let mut all_script_coverages = collect_coverage_helper(&args.pattern, &args.filters).await?;
+    // 先记录文件状态再读取，读取过程中被重写的文件在监听模式下会再合并一次
+    let mut seen = HashSet::new();
+    if args.watch {
+        for p in glob_abs(&args.pattern)? {
+            seen.insert(file_stamp(&p).await?);
+        }
+    }
+    let mut all_script_coverages =
+        collect_coverage_helper(&args.pattern, &args.filters, args.module_wrapper).await?;
+    // 监听模式下每个覆盖率文件的结果要单独保存，文件被重写的时候才能整个替换
+    if !args.per_test && !args.no_v8_merge && !args.watch {
+        // 相同的脚本先在 v8 区间上合并，每个脚本只需要映射一次
+        let all = all_script_coverages.drain().flat_map(|(_, v)| v).collect();
+        all_script_coverages.insert("合并的v8覆盖率".to_string(), merge_script_coverages(all));
+    }
 
     let output_dir = path_to_abs(&args.output)?.to_str().unwrap().to_string();
-    fs::create_dir_all(format!("{}/.nyc_output/", output_dir)).await?;
+    let layout = OutputLayout::new(
+        Path::new(&output_dir),
+        &args.report_dir,
+        &args.temp_dir,
+        &args.output_file,
+        !args.compact,
+    );
+    layout.prepare().await?;
 
+    let link_options = LinkOptions {
+        respect_ignore_list: !args.include_ignored,
+    };
+    let cache = if args.no_cache {
+        None
+    } else {
+        let project_root = path_to_abs(&args.project_root)?;
+        match &args.cache_dir {
+            Some(dir) => Some(project_root.join(dir)),
+            None => default_cache_dir(&project_root),
+        }
+        .map(StatementCache::new)
+    };
     let statement_data = build_statements_from_local(
         &args.source_map_base,
         &args.url_base,
         &output_dir,
         &source_relocate,
+        &link_options,
+        args.materialize_sources,
+        cache.as_ref(),
     )
     .await?;
+    if let Some(cache) = &cache {
+        if let Err(e) = cache.prune().await {
+            warn!("清理缓存失败: {}", e);
+        }
+    }
 
-    let mut merged_result: HashMap<String, IstanbulCov> = HashMap::new();
+    let mut results = TestResults::default();
     // 创建空覆盖率报告
     all_script_coverages.insert(
         "默认空覆盖率".to_string(),
@@ -71,39 +164,337 @@ pub async fn exec(args: &ConvertArgs) -> Result<()> {
                 url: k.to_string(),
                 source: "".to_string(),
                 functions: vec![],
+                script_url: "".to_string(),
+                binary: false,
             })
             .collect(),
     );
 
+    let mut no_map_scripts = BTreeSet::new();
     for (test_name, sc_arr) in all_script_coverages {
-        for sc in sc_arr {
-            info!(test_name = test_name, url = sc.url, "关联ScriptCoverage");
-            match handle_script_coverage(&statement_data, &sc) {
-                Ok(report) => {
-                    trace!("执行 nyc 生成报告");
-                    for (k, v) in report {
-                        let e = merged_result.entry(k).or_insert(IstanbulCov::default());
-                        e.path = v.path;
-                        for (index, s) in v.statement_map {
-                            e.statement_map.insert(index, s);
-                        }
-                        for (index, count) in v.s {
-                            let c = e.s.entry(index).or_insert(0);
-                            *c += count;
+        let test_result = convert_test(
+            args,
+            &statement_data,
+            &output_dir,
+            &test_name,
+            sc_arr,
+            &mut no_map_scripts,
+        )
+        .await;
+        finish_test(args, &layout, &test_name, test_result, &mut results).await?;
+    }
+
+    if let Some(css_pattern) = &args.css_pattern {
+        let css_maps = build_css_source_maps(&args.source_map_base, &source_relocate).await?;
+        for (test_name, css_arr) in collect_css_coverage(css_pattern, &args.filters).await? {
+            let mut test_result: HashMap<String, IstanbulCov> = HashMap::new();
+            for cov in css_arr {
+                info!(test_name = test_name, url = cov.url, "关联CssCoverage");
+                match handle_css_coverage(
+                    &cov,
+                    &css_maps,
+                    &source_relocate,
+                    &output_dir,
+                    &link_options,
+                )
+                .await
+                {
+                    Ok(report) => {
+                        for (k, v) in report {
+                            test_result.entry(k).or_default().merge(v);
                         }
                     }
+                    Err(e) => error!("处理CssCoverage出错了:{}", e),
+                }
+            }
+            finish_test(args, &layout, &test_name, test_result, &mut results).await?;
+        }
+    }
+    warn_no_map_scripts(args, &no_map_scripts);
+    write_outputs(args, &layout, &results).await?;
+    info!("搞定");
+
+    if args.watch {
+        let mut state = WatchState {
+            statement_data,
+            output_dir,
+            layout,
+            results,
+            seen,
+            failures: HashMap::new(),
+        };
+        state.watch(args).await?;
+    }
+    Ok(())
+}
+
+/// 默认的缓存目录，不主动在用户的项目里面创建 node_modules
+///
+/// 项目里面已经有 node_modules 的时候和其他工具一样放在 `node_modules/.cache` 下面，
+/// 否则放在系统的缓存目录，都找不到的时候不使用缓存。缓存 key 里面有 project_dir，多个项目共用一个目录没有问题
+fn default_cache_dir(project_root: &Path) -> Option<PathBuf> {
+    let node_modules = project_root.join("node_modules");
+    if node_modules.is_dir() {
+        return Some(node_modules.join(".cache/v8-to-istanbul"));
+    }
+    let env_dir = |name: &str| {
+        std::env::var_os(name)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    };
+    let system = if cfg!(windows) {
+        env_dir("LOCALAPPDATA")
+    } else if cfg!(target_os = "macos") {
+        env_dir("HOME").map(|h| h.join("Library/Caches"))
+    } else {
+        env_dir("XDG_CACHE_HOME").or_else(|| env_dir("HOME").map(|h| h.join(".cache")))
+    };
+    if system.is_none() {
+        debug!("没有找到缓存目录，不使用预处理缓存");
+    }
+    system.map(|d| d.join("v8-to-istanbul"))
+}
+
+/// 转换一个测试文件里面的所有脚本
+async fn convert_test(
+    args: &ConvertArgs,
+    statement_data: &HashMap<String, Statement>,
+    output_dir: &str,
+    test_name: &str,
+    sc_arr: Vec<ScriptCoverage>,
+    no_map_scripts: &mut BTreeSet<String>,
+) -> HashMap<String, IstanbulCov> {
+    let mut test_result: HashMap<String, IstanbulCov> = HashMap::new();
+    for sc in sc_arr {
+        info!(test_name = test_name, url = sc.url, "关联ScriptCoverage");
+        let report = if statement_data.contains_key(&sc.url) {
+            handle_script_coverage(statement_data, &sc)
+        } else {
+            no_map_scripts.insert(sc.url.clone());
+            if !args.generated_coverage {
+                continue;
+            }
+            handle_generated_coverage(&sc, output_dir).await
+        };
+        match report {
+            Ok(report) => {
+                trace!("执行 nyc 生成报告");
+                for (k, v) in report {
+                    test_result.entry(k).or_default().merge(v);
+                }
+            }
+            Err(e) => error!("处理ScriptCoverage出错了:{}", e),
+        };
+    }
+    test_result
+}
+
+fn warn_no_map_scripts(args: &ConvertArgs, no_map_scripts: &BTreeSet<String>) {
+    if no_map_scripts.is_empty() {
+        return;
+    }
+    if args.generated_coverage {
+        warn!(
+            "以下脚本没有 source map，使用生成代码统计覆盖率: {:?}",
+            no_map_scripts
+        );
+    } else {
+        warn!("以下脚本没有 source map，已跳过: {:?}", no_map_scripts);
+    }
+}
+
+/// 每个测试的转换结果，按测试名保存
+///
+/// 监听模式下覆盖率文件被重写的时候整个替换，合并之后的结果每次都重新计算
+#[derive(Default)]
+struct TestResults {
+    coverages: HashMap<String, HashMap<String, IstanbulCov>>,
+    processes: BTreeMap<String, ProcessInfo>,
+}
+
+impl TestResults {
+    fn merged(&self) -> HashMap<String, IstanbulCov> {
+        let mut merged: HashMap<String, IstanbulCov> = HashMap::new();
+        for test_result in self.coverages.values() {
+            for (k, v) in test_result {
+                merged.entry(k.clone()).or_default().merge(v.clone());
+            }
+        }
+        merged
+    }
+
+    fn remove(&mut self, test_name: &str) {
+        self.coverages.remove(test_name);
+        self.processes.remove(test_name);
+    }
+}
+
+/// 输出合并之后的覆盖率和各种报告
+async fn write_outputs(
+    args: &ConvertArgs,
+    layout: &OutputLayout,
+    results: &TestResults,
+) -> Result<()> {
+    if args.per_test {
+        layout
+            .write_process_index(results.processes.values())
+            .await?;
+    }
+    let mut merged_result = results.merged();
+    if args.emit_lines {
+        for v in merged_result.values_mut() {
+            fill_legacy_lines(v, args.line_mode);
+        }
+    }
+
+    layout.write_merged(&merged_result).await?;
+
+    write_reports(
+        &args.reporter,
+        &merged_result,
+        &ReportOptions {
+            layout: layout.clone(),
+            line_mode: args.line_mode,
+            skip_full: args.skip_full,
+            project_root: path_to_abs(&args.project_root)?,
+        },
+    )
+    .await
+}
+
+/// 覆盖率文件连续解析失败这么多次之后告警，文件更新之前不再重试
+const WATCH_PARSE_ATTEMPTS: u32 = 5;
+
+/// 覆盖率文件的路径、修改时间和大小，文件被重写之后会重新合并
+type FileStamp = (String, Option<SystemTime>, u64);
+
+async fn file_stamp(p: &Path) -> Result<FileStamp> {
+    let meta = fs::metadata(p).await?;
+    Ok((
+        p.to_string_lossy().to_string(),
+        meta.modified().ok(),
+        meta.len(),
+    ))
+}
+
+/// 监听模式下常驻内存的数据，source map 只在启动的时候解析一次
+struct WatchState {
+    statement_data: HashMap<String, Statement>,
+    output_dir: String,
+    layout: OutputLayout,
+    results: TestResults,
+    /// 已经合并过的覆盖率文件
+    seen: HashSet<FileStamp>,
+    /// 解析失败的覆盖率文件和失败次数
+    failures: HashMap<FileStamp, u32>,
+}
+
+impl WatchState {
+    async fn watch(&mut self, args: &ConvertArgs) -> Result<()> {
+        info!(pattern = args.pattern, "监听新的覆盖率文件，按 Ctrl-C 退出");
+        let mut interval = tokio::time::interval(Duration::from_millis(args.watch_interval.max(1)));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.poll(args).await {
+                error!("增量转换出错了:{}", e);
+            }
+        }
+    }
+
+    /// 扫描一次 pattern，返回新合并的文件个数
+    async fn poll(&mut self, args: &ConvertArgs) -> Result<usize> {
+        let mut no_map_scripts = BTreeSet::new();
+        let mut n = 0;
+        for p in glob_abs(&args.pattern)? {
+            let stamp = match file_stamp(&p).await {
+                Ok(stamp) => stamp,
+                Err(e) => {
+                    debug!("读取覆盖率文件状态失败 {}: {}", p.to_string_lossy(), e);
+                    continue;
+                }
+            };
+            if self.seen.contains(&stamp) {
+                continue;
+            }
+            let test_name = stamp.0.clone();
+            // 文件可能还没有写完，解析失败的下次再试
+            let sc_arr = match collect_coverage_file(&p, &args.filters, args.module_wrapper).await {
+                Ok(sc_arr) => sc_arr,
+                Err(e) => {
+                    let attempts = self.failures.entry(stamp.clone()).or_default();
+                    *attempts += 1;
+                    if *attempts < WATCH_PARSE_ATTEMPTS {
+                        debug!("覆盖率文件暂时无法解析，稍后重试 {}: {}", test_name, e);
+                        continue;
+                    }
+                    warn!(
+                        "覆盖率文件连续 {} 次解析失败，文件更新之前不再重试 {}: {}",
+                        attempts, test_name, e
+                    );
+                    self.failures.remove(&stamp);
+                    self.seen.insert(stamp);
+                    continue;
                 }
-                Err(e) => error!("处理ScriptCoverage出错了:{}", e),
             };
+            self.failures.remove(&stamp);
+            let len = self.seen.len();
+            self.seen.retain(|s| s.0 != test_name);
+            if self.seen.len() < len {
+                info!("覆盖率文件有更新，重新合并 {}", test_name);
+                self.results.remove(&test_name);
+            } else {
+                info!("合并新的覆盖率文件 {}", test_name);
+            }
+            self.seen.insert(stamp);
+            let test_result = convert_test(
+                args,
+                &self.statement_data,
+                &self.output_dir,
+                &test_name,
+                sc_arr,
+                &mut no_map_scripts,
+            )
+            .await;
+            finish_test(
+                args,
+                &self.layout,
+                &test_name,
+                test_result,
+                &mut self.results,
+            )
+            .await?;
+            n += 1;
+        }
+        if n > 0 {
+            warn_no_map_scripts(args, &no_map_scripts);
+            write_outputs(args, &self.layout, &self.results).await?;
+            info!("已经更新报告，新增 {} 个覆盖率文件", n);
         }
+        Ok(n)
     }
+}
 
-    let d = format!("{}/.nyc_output/merged.json", output_dir);
-    let b = serde_json::to_string_pretty(&merged_result)?;
-    fs::write(&d, b)
-        .await
-        .map_err(|e| anyhow!("写入报告失败 [{}] {}", &d, e))?;
-    info!("搞定");
+/// 一个测试文件处理完之后保存到这个测试的结果里面，按需单独输出
+async fn finish_test(
+    args: &ConvertArgs,
+    layout: &OutputLayout,
+    test_name: &str,
+    test_result: HashMap<String, IstanbulCov>,
+    results: &mut TestResults,
+) -> Result<()> {
+    let saved = results.coverages.entry(test_name.to_string()).or_default();
+    for (k, v) in test_result {
+        saved.entry(k).or_default().merge(v);
+    }
+    if args.per_test {
+        if args.emit_lines {
+            for v in saved.values_mut() {
+                fill_legacy_lines(v, args.line_mode);
+            }
+        }
+        let info = layout.write_process(test_name, saved).await?;
+        results.processes.insert(test_name.to_string(), info);
+    }
     Ok(())
 }
 
@@ -120,28 +511,14 @@ fn handle_script_coverage(
             return Ok(HashMap::new());
         }
     };
-    let root = Rc::new(RefCell::new(CoverRangeNode::new(&CoverageRange {
-        start_offset: 0,
-        end_offset: sc.source.len() as u32,
-        count: 0,
-    })));
-    trace!("构造覆盖率搜索树");
-    build_coverage_range_tree(root.clone(), &sc.functions);
-    let cov_tree = read_only(root);
+    let cov_tree = coverage_tree(sc);
     trace!("搜索覆盖率");
-    let vm = statement
+    let vm: Vec<MappingItem> = statement
         .mapping
         .par_iter()
         .map(|m| {
             let mut m = m.clone();
-            if let Some(n) = find_root_value_only(
-                &cov_tree,
-                &CoverageRange {
-                    start_offset: m.generated_column,
-                    end_offset: m.last_generated_column,
-                    count: 0,
-                },
-            ) {
+            if let Some(n) = mapping_count(&cov_tree, &m) {
                 m.count = n;
             }
             m
@@ -149,11 +526,62 @@ fn handle_script_coverage(
         .collect();
     trace!("搜索覆盖率完成");
 
+    trace!("关联函数覆盖率");
+    let code: Vec<char> = sc.source.chars().collect();
+    let fns = function_link(&sc.functions, &code, statement);
+
     trace!("生成istanbul报告");
-    let report = istanbul::from(&vm, &statement.code_dir);
+    let mut report = istanbul::from(&vm, &fns, &statement.code_dir);
+    let count_mode = sc.count_mode();
+    for v in report.values_mut() {
+        v.count_mode = count_mode;
+    }
     Ok(report)
 }
-fn relocate(pattern: &str) -> Result<(Regex, String)> {
+
+#[instrument(skip_all, fields(script = sc.url))]
+async fn handle_generated_coverage(
+    sc: &ScriptCoverage,
+    output_dir: &str,
+) -> Result<HashMap<String, IstanbulCov>> {
+    let _timer = Timer::new("生成代码覆盖率报告");
+    let rel = generated_path(sc);
+    if sc.source.is_empty() || !is_legal_source_path(&rel) {
+        return Err(anyhow!("脚本没有源码或者路径不合法"));
+    }
+    let path = safe_join(&PathBuf::from(output_dir).join("generated"), &rel)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write(&path, &sc.source).await?;
+
+    let parsed = parse_code(&sc.source, &sc.url)?;
+    let cov_tree = coverage_tree(sc);
+    let path = path.to_string_lossy().to_string();
+    let mut report = istanbul::from_generated(&parsed, &cov_tree, &path);
+    report.count_mode = sc.count_mode();
+    Ok(HashMap::from([(path, report)]))
+}
+
+/// 生成代码在 `generated` 目录下面的路径，保留 url 的 host 和路径，不同目录下的同名脚本不会互相覆盖
+fn generated_path(sc: &ScriptCoverage) -> String {
+    let path = match Url::parse(&sc.script_url) {
+        Ok(u) => format!(
+            "{}{}",
+            u.host_str().unwrap_or_default(),
+            percent_decode_str(u.path()).decode_utf8_lossy()
+        ),
+        Err(_) => sc.script_url.clone(),
+    };
+    let path = path.trim_start_matches('/');
+    if path.is_empty() || path.ends_with('/') {
+        sc.url.clone()
+    } else {
+        path.to_string()
+    }
+}
+
+pub fn relocate(pattern: &str) -> Result<(Regex, String)> {
     if pattern.is_empty() {
         return Err(anyhow!("pattern is empty"));
     }
@@ -171,9 +599,134 @@ fn relocate(pattern: &str) -> Result<(Regex, String)> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        args: ConvertArgs,
+    }
 
     #[test]
     fn test_relocate() {
         dbg!(relocate(r"%webpack://%%").unwrap());
     }
+
+    #[test]
+    fn test_generated_path() {
+        let sc = |script_url: &str| ScriptCoverage {
+            url: "app.js".to_string(),
+            source: "".to_string(),
+            functions: vec![],
+            script_url: script_url.to_string(),
+            binary: false,
+        };
+        assert_eq!(
+            generated_path(&sc("https://a.com/x/app.js?v=1")),
+            "a.com/x/app.js"
+        );
+        assert_eq!(
+            generated_path(&sc("https://a.com/y/app.js")),
+            "a.com/y/app.js"
+        );
+        assert_eq!(
+            generated_path(&sc("file:///work/dist/my%20app.js")),
+            "work/dist/my app.js"
+        );
+        assert_eq!(generated_path(&sc("https://a.com/")), "app.js");
+        assert_eq!(generated_path(&sc("")), "app.js");
+        // url 解析的时候已经去掉了 `..`
+        assert_eq!(
+            generated_path(&sc("https://a.com/%2e%2e/%2e%2e/etc/passwd")),
+            "a.com/etc/passwd"
+        );
+    }
+
+    #[test]
+    fn test_default_cache_dir() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("v8-cache-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        // 没有 node_modules 的项目不会在项目里面创建缓存目录
+        if let Some(d) = default_cache_dir(&dir) {
+            assert!(!d.starts_with(&dir));
+        }
+        std::fs::create_dir_all(dir.join("node_modules"))?;
+        assert_eq!(
+            default_cache_dir(&dir),
+            Some(dir.join("node_modules/.cache/v8-to-istanbul"))
+        );
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_poll() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("v8-watch-{}", std::process::id()));
+        let input = dir.join("in");
+        fs::create_dir_all(&input).await?;
+        let output = dir.join("out");
+        let args = TestCli::try_parse_from([
+            "convert",
+            "--pattern",
+            &input.join("*.json").to_string_lossy(),
+            "--output",
+            &output.to_string_lossy(),
+            "--source-map-base",
+            "",
+            "--generated-coverage",
+            "--per-test",
+        ])?
+        .args;
+        let layout = OutputLayout::new(&output, ".", ".nyc_output", "merged.json", false);
+        layout.prepare().await?;
+        let mut state = WatchState {
+            statement_data: HashMap::new(),
+            output_dir: output.to_string_lossy().to_string(),
+            layout,
+            results: TestResults::default(),
+            seen: HashSet::new(),
+            failures: HashMap::new(),
+        };
+        let coverage = include_str!("../../tests/base/v8-coverage.json");
+        let count = |state: &WatchState| -> u32 {
+            state
+                .results
+                .merged()
+                .values()
+                .flat_map(|ic| ic.f.values())
+                .sum()
+        };
+
+        fs::write(input.join("a.json"), coverage).await?;
+        assert_eq!(state.poll(&args).await?, 1);
+        let once = count(&state);
+        assert!(once > 0);
+        assert!(output.join("merged.json").exists());
+        assert_eq!(state.poll(&args).await?, 0);
+
+        // 同一个文件被重写之后替换掉旧的结果，不会重复计数
+        fs::write(input.join("a.json"), format!("{}\n", coverage)).await?;
+        assert_eq!(state.poll(&args).await?, 1);
+        assert_eq!(count(&state), once);
+        assert_eq!(state.results.coverages.len(), 1);
+        assert_eq!(state.results.processes.len(), 1);
+
+        // 写了一半的文件先重试，连续失败之后不再处理
+        fs::write(input.join("b.json"), &coverage[..100]).await?;
+        for _ in 1..WATCH_PARSE_ATTEMPTS {
+            assert_eq!(state.poll(&args).await?, 0);
+        }
+        assert_eq!(
+            state.failures.values().copied().max(),
+            Some(WATCH_PARSE_ATTEMPTS - 1)
+        );
+        assert_eq!(state.poll(&args).await?, 0);
+        assert!(state.failures.is_empty());
+        assert_eq!(state.seen.len(), 2);
+        assert_eq!(state.poll(&args).await?, 0);
+        assert!(state.failures.is_empty());
+
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,339 @@
+use crate::cmd::convert::relocate;
+use crate::format::script_coverage::{
+    collect_coverage_helper, coverage_tree, find_root_node, CoverRangeNodeRead, CoverageRange,
+    ModuleWrapper,
+};
+use crate::format::v8_merge::merge_script_coverages;
+use crate::format::MappingItem;
+use crate::fputil::path_to_abs;
+use crate::statement::build_statements_from_local;
+use crate::translate::LinkOptions;
+use anyhow::{anyhow, Result};
+use clap::Args;
+use std::collections::{BTreeMap, HashMap};
+use tracing::{instrument, warn};
+
+#[derive(Args, Debug)]
+pub struct InspectArgs {
+    #[arg(long)]
+    file: String, // 源码文件，和 source map 里面 sources 的结尾匹配
+    #[arg(long)]
+    line: Option<u32>, // 源码行号，从 1 开始，和 istanbul 报告一致
+    #[arg(long)]
+    column: Option<u32>, // 源码列号，从 0 开始，不指定的时候输出整行
+    #[arg(long)]
+    dump: bool, // 按源码顺序输出这个文件的全部 MappingItem
+    #[arg(long)]
+    source_map_base: String, // 本地 source map 文件，和 convert 一致
+    #[arg(long)]
+    url_base: Option<String>, // 用来补全 source map 里面 file 的路径
+    #[arg(long)]
+    source_relocate: Option<String>, // 用来替换 source map 里面 sources 的路径
+    #[arg(long)]
+    pattern: Option<String>, // v8 覆盖率文件，不指定的时候只输出映射关系
+    #[arg(long)]
+    filters: Vec<String>,
+    #[arg(long, default_value = "auto")]
+    module_wrapper: ModuleWrapper, // auto、esm、cjs 或者自定义加载器在源码前面加的字符数
+    #[arg(long)]
+    include_ignored: bool, // 不使用 source map 里面的 ignoreList 过滤第三方代码
+    #[arg(long, default_value = ".")]
+    output: String, // convert 的输出目录，sourcesContent 缺失的时候从这里读取源码
+}
+
+/// 输出片段的最大字符数
+const SNIPPET_LIMIT: usize = 60;
+
+#[instrument]
+pub async fn exec(args: &InspectArgs) -> Result<()> {
+    print!("{}", run(args).await?);
+    Ok(())
+}
+
+async fn run(args: &InspectArgs) -> Result<String> {
+    if !args.dump && args.line.is_none() {
+        return Err(anyhow!("需要指定 --line 或者 --dump"));
+    }
+    let source_relocate = match &args.source_relocate {
+        Some(s) => Some(relocate(s)?),
+        None => None,
+    };
+    let project_dir = path_to_abs(&args.output)?.to_string_lossy().to_string();
+    let link_options = LinkOptions {
+        respect_ignore_list: !args.include_ignored,
+    };
+    let statements = build_statements_from_local(
+        &args.source_map_base,
+        &args.url_base,
+        &project_dir,
+        &source_relocate,
+        &link_options,
+        false,
+        None,
+    )
+    .await?;
+
+    let mut trees: HashMap<String, CoverRangeNodeRead> = HashMap::new();
+    if let Some(pattern) = &args.pattern {
+        let all = collect_coverage_helper(pattern, &args.filters, args.module_wrapper).await?;
+        for sc in merge_script_coverages(all.into_values().flatten().collect()) {
+            trees.insert(sc.url.clone(), coverage_tree(&sc));
+        }
+    }
+
+    let mut out = String::new();
+    let mut found = false;
+    let mut scripts: Vec<_> = statements.iter().collect();
+    scripts.sort_by_key(|(k, _)| k.as_str());
+    for (script, statement) in scripts {
+        let items: Vec<&MappingItem> = statement
+            .mapping
+            .iter()
+            .filter(|m| source_matches(&m.source, &args.file))
+            .filter(|m| {
+                args.dump || covers(m, args.line.unwrap_or(1).saturating_sub(1), args.column)
+            })
+            .collect();
+        if items.is_empty() {
+            continue;
+        }
+        found = true;
+        let code: Vec<char> = statement.code.chars().collect();
+        let tree = trees.get(script);
+        if args.pattern.is_some() && tree.is_none() {
+            warn!("覆盖率文件里面没有这个脚本: {}", script);
+        }
+        out.push_str(&format!("== {} ({} 个 MappingItem)\n", script, items.len()));
+        if args.dump {
+            let sources = statement.original_sources().await;
+            out.push_str(&render_dump(&items, &code, tree, &sources));
+        } else {
+            for m in items {
+                out.push_str(&describe(m, &code, tree));
+                out.push('\n');
+            }
+        }
+    }
+    if !found {
+        return Err(anyhow!("没有找到 {} 对应的 MappingItem", args.file));
+    }
+    Ok(out)
+}
+
+fn source_matches(source: &str, file: &str) -> bool {
+    let file = file.trim_start_matches("./");
+    source == file || source.ends_with(&format!("/{}", file))
+}
+
+/// `line` 从 0 开始，和 MappingItem 一致
+fn covers(m: &MappingItem, line: u32, column: Option<u32>) -> bool {
+    match column {
+        Some(column) => {
+            (m.original_line, m.original_column) <= (line, column)
+                && (line, column) <= (m.last_original_line, m.last_original_column)
+        }
+        None => m.original_line <= line && line <= m.last_original_line,
+    }
+}
+
+/// 生成代码 `[start, end]` 的内容，过长的时候截断
+fn snippet(code: &[char], start: u32, end: u32) -> String {
+    let start = (start as usize).min(code.len());
+    let end = (end as usize + 1).clamp(start, code.len());
+    let s: String = code[start..end].iter().take(SNIPPET_LIMIT).collect();
+    if end - start > SNIPPET_LIMIT {
+        format!("{:?}...", s)
+    } else {
+        format!("{:?}", s)
+    }
+}
+
+/// 决定这个 MappingItem 计数的 v8 区间
+fn v8_range(m: &MappingItem, tree: Option<&CoverRangeNodeRead>) -> String {
+    let Some(tree) = tree else {
+        return "无覆盖率数据".to_string();
+    };
+    let range = CoverageRange {
+        start_offset: m.generated_column,
+        end_offset: m.last_generated_column,
+        count: 0,
+    };
+    match find_root_node(tree, &range) {
+        // 根节点是覆盖整个脚本的虚拟区间
+        Some(n) if std::ptr::eq(n, tree) => "不在任何 v8 区间内 count=0".to_string(),
+        Some(n) => format!("v8 [{}, {}) count={}", n.left, n.right, n.value),
+        None => "超出脚本范围".to_string(),
+    }
+}
+
+fn describe(m: &MappingItem, code: &[char], tree: Option<&CoverRangeNodeRead>) -> String {
+    format!(
+        "#{} {} {}:{}-{}:{} 生成代码 [{}, {}] {} {}",
+        m.idx,
+        m.source,
+        m.original_line + 1,
+        m.original_column,
+        m.last_original_line + 1,
+        m.last_original_column,
+        m.generated_column,
+        m.last_generated_column,
+        v8_range(m, tree),
+        snippet(code, m.generated_column, m.last_generated_column)
+    )
+}
+
+/// `--file` 可能匹配到多个源码文件，每个文件单独输出
+fn render_dump(
+    items: &[&MappingItem],
+    code: &[char],
+    tree: Option<&CoverRangeNodeRead>,
+    sources: &HashMap<String, String>,
+) -> String {
+    let mut by_source: BTreeMap<&str, Vec<&MappingItem>> = BTreeMap::new();
+    for m in items {
+        by_source.entry(&m.source).or_default().push(m);
+    }
+    let mut out = String::new();
+    for (source, items) in by_source {
+        out.push_str(&format!("-- {}\n", source));
+        let original = sources.get(source).map(String::as_str);
+        out.push_str(&render_listing(&items, code, tree, original));
+    }
+    out
+}
+
+/// 按源码行输出，每行源码下面列出从这一行开始的 MappingItem
+fn render_listing(
+    items: &[&MappingItem],
+    code: &[char],
+    tree: Option<&CoverRangeNodeRead>,
+    original: Option<&str>,
+) -> String {
+    let mut by_line: BTreeMap<u32, Vec<&MappingItem>> = BTreeMap::new();
+    for m in items {
+        by_line.entry(m.original_line).or_default().push(m);
+    }
+    let lines: Vec<&str> = original.map(|s| s.lines().collect()).unwrap_or_default();
+    let mut out = String::new();
+    for (line, mut items) in by_line {
+        items.sort_by_key(|m| (m.original_column, m.generated_column));
+        let text = lines.get(line as usize).copied().unwrap_or_default();
+        out.push_str(&format!("{:>5} | {}\n", line + 1, text));
+        for m in items {
+            out.push_str(&format!("      | {}\n", describe(m, code, tree)));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args() -> InspectArgs {
+        InspectArgs {
+            file: "main.js".to_string(),
+            line: Some(1),
+            column: None,
+            dump: false,
+            source_map_base: concat!(env!("CARGO_MANIFEST_DIR"), "/tests/base/*.map").to_string(),
+            url_base: Some(
+                concat!(env!("CARGO_MANIFEST_DIR"), "/tests/base/main.min.js").to_string(),
+            ),
+            source_relocate: None,
+            pattern: Some(
+                concat!(env!("CARGO_MANIFEST_DIR"), "/tests/base/v8-coverage.json").to_string(),
+            ),
+            filters: vec![],
+            module_wrapper: ModuleWrapper::Auto,
+            include_ignored: false,
+            output: "/p".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_snippet() {
+        let code: Vec<char> = "let a = 1;\nf()".chars().collect();
+        assert_eq!(snippet(&code, 4, 4), r#""a""#);
+        assert_eq!(snippet(&code, 8, 11), r#""1;\nf""#);
+        assert_eq!(snippet(&code, 12, 100), r#""()""#);
+        let long: Vec<char> = "x".repeat(100).chars().collect();
+        assert!(snippet(&long, 0, 99).ends_with("..."));
+    }
+
+    #[test]
+    fn test_source_matches() {
+        assert!(source_matches("src/main.js", "main.js"));
+        assert!(source_matches("src/main.js", "./src/main.js"));
+        assert!(!source_matches("src/domain.js", "main.js"));
+    }
+
+    #[test]
+    fn test_render_dump() {
+        let item = |source: &str, line, gs| MappingItem {
+            source: source.to_string(),
+            generated_column: gs,
+            last_generated_column: gs,
+            original_line: line,
+            original_column: 0,
+            last_original_line: line,
+            last_original_column: 0,
+            count: 0,
+            idx: gs as usize,
+            skip: false,
+        };
+        let items = [
+            item("b/main.js", 0, 2),
+            item("a/main.js", 1, 0),
+            item("b/main.js", 1, 1),
+        ];
+        let items: Vec<&MappingItem> = items.iter().collect();
+        let code: Vec<char> = "abc".chars().collect();
+        let sources = HashMap::from([
+            ("a/main.js".to_string(), "a0\na1\n".to_string()),
+            ("b/main.js".to_string(), "b0\nb1\n".to_string()),
+        ]);
+        let out = render_dump(&items, &code, None, &sources);
+        let lines: Vec<&str> = out.lines().collect();
+        // 每个源码文件的行号和源码都来自它自己
+        assert_eq!(lines[0], "-- a/main.js");
+        assert_eq!(lines[1], "    2 | a1");
+        assert_eq!(lines[3], "-- b/main.js");
+        assert_eq!(lines[4], "    1 | b0");
+        assert_eq!(lines[6], "    2 | b1");
+    }
+
+    #[tokio::test]
+    async fn test_inspect_line() -> Result<()> {
+        let out = run(&InspectArgs {
+            column: Some(2),
+            ..args()
+        })
+        .await?;
+        assert!(out.starts_with("== main.min.js"));
+        assert!(out.contains("#0 src/main.js 1:2-1:7 生成代码 [7, 10]"));
+        assert!(out.contains("v8 ["));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inspect_dump() -> Result<()> {
+        let out = run(&InspectArgs {
+            dump: true,
+            pattern: None,
+            ..args()
+        })
+        .await?;
+        assert!(out.contains("-- src/main.js\n    1 | "));
+        assert!(out.contains("无覆盖率数据"));
+
+        let e = run(&InspectArgs {
+            file: "missing.js".to_string(),
+            ..args()
+        })
+        .await
+        .unwrap_err();
+        assert!(e.to_string().contains("missing.js"));
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use crate::format::input::parse_coverage_input;
+use crate::format::script_coverage::ScriptCoverageRaw;
+use crate::format::v8_merge::merge_raw_script_coverages;
+use crate::fputil::glob_abs;
+use crate::timer::Timer;
+use anyhow::{anyhow, Result};
+use clap::Args;
+use serde::Serialize;
+use std::path::Path;
+use tokio::fs;
+use tracing::{debug, info, instrument};
+
+#[derive(Args, Debug)]
+pub struct MergeV8Args {
+    #[arg(long)]
+    pattern: String, // 需要合并的覆盖率文件，支持所有 convert 能识别的格式
+    #[arg(long)]
+    output: String, // 合并之后的 v8 覆盖率文件
+    #[arg(long)]
+    compact: bool, // 输出不带缩进的 JSON
+}
+
+/// 和 CDP `Profiler.takePreciseCoverage` 的返回值格式一致
+#[derive(Serialize)]
+struct MergedCoverage {
+    result: Vec<ScriptCoverageRaw>,
+}
+
+#[instrument]
+pub async fn exec(args: &MergeV8Args) -> Result<()> {
+    let _timer = Timer::new("合并 v8 覆盖率");
+    let files = glob_abs(&args.pattern)?;
+    info!("待合并的覆盖率报告文件列表 {:?}", &files);
+    let merged = merge_files(&files)?;
+    let s = if args.compact {
+        serde_json::to_string(&merged)?
+    } else {
+        serde_json::to_string_pretty(&merged)?
+    };
+    if let Some(parent) = Path::new(&args.output).parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write(&args.output, s).await?;
+    info!(scripts = merged.result.len(), "已经写入 {}", args.output);
+    Ok(())
+}
+
+fn merge_files<P: AsRef<Path>>(files: &[P]) -> Result<MergedCoverage> {
+    let mut all = vec![];
+    for p in files {
+        let p = p.as_ref();
+        debug!("处理覆盖率文件 {}", p.to_string_lossy());
+        let s = std::fs::read_to_string(p)?;
+        let (_, sc_arr) =
+            parse_coverage_input(&s).map_err(|e| anyhow!("解析{:?}出错, {}", p.to_str(), e))?;
+        all.extend(sc_arr);
+    }
+    Ok(MergedCoverage {
+        result: merge_raw_script_coverages(all),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge_files() -> Result<()> {
+        let file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/base/v8-coverage.json");
+        let single = merge_files(&[file])?;
+        let merged = merge_files(&[file, file])?;
+        assert_eq!(single.result.len(), merged.result.len());
+        for (a, b) in single.result.iter().zip(merged.result.iter()) {
+            assert_eq!(a.url, b.url);
+            assert_eq!(a.functions.len(), b.functions.len());
+            for (fa, fb) in a.functions.iter().zip(b.functions.iter()) {
+                assert_eq!(fa.ranges.len(), fb.ranges.len());
+                for (ra, rb) in fa.ranges.iter().zip(fb.ranges.iter()) {
+                    assert_eq!(ra.count * 2, rb.count);
+                }
+            }
+        }
+
+        // 输出的文件可以重新作为输入
+        let s = serde_json::to_string(&merged)?;
+        let (_, again) = parse_coverage_input(&s)?;
+        assert_eq!(again.len(), merged.result.len());
+        Ok(())
+    }
+}
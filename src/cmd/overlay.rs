@@ -0,0 +1,105 @@
+use crate::cmd::convert::relocate;
+use crate::format::overlay::render_overlay;
+use crate::format::script_coverage::{
+    collect_coverage_helper, coverage_tree, ModuleWrapper, ScriptCoverage,
+};
+use crate::format::v8_merge::merge_script_coverages;
+use crate::format::xml_escape;
+use crate::fputil::{path_to_abs, safe_join};
+use crate::statement::build_statements_from_local;
+use crate::timer::Timer;
+use crate::translate::LinkOptions;
+use anyhow::Result;
+use clap::Args;
+use std::collections::HashMap;
+use tokio::fs;
+use tracing::{info, instrument, warn};
+
+#[derive(Args, Debug)]
+pub struct OverlayArgs {
+    #[arg(long)]
+    pattern: String, // v8 覆盖率文件，多个文件会先合并
+    #[arg(long)]
+    filters: Vec<String>,
+    #[arg(long)]
+    source_map_base: String, // 本地 source map 文件，和 convert 一致
+    #[arg(long)]
+    url_base: Option<String>, // 用来补全 source map 里面 file 的路径
+    #[arg(long)]
+    source_relocate: Option<String>, // 用来替换 source map 里面 sources 的路径
+    #[arg(long, default_value = "auto")]
+    module_wrapper: ModuleWrapper, // auto、esm、cjs 或者自定义加载器在源码前面加的字符数
+    #[arg(long)]
+    include_ignored: bool, // 不使用 source map 里面的 ignoreList 过滤第三方代码
+    #[arg(long, default_value = ".")]
+    output: String, // html 输出到这个目录下面的 overlay 目录，可以用 serve 查看
+}
+
+#[instrument]
+pub async fn exec(args: &OverlayArgs) -> Result<()> {
+    let _timer = Timer::new("生成 overlay");
+    let source_relocate = match &args.source_relocate {
+        Some(s) => Some(relocate(s)?),
+        None => None,
+    };
+    let output_dir = path_to_abs(&args.output)?;
+    let project_dir = output_dir.to_string_lossy().to_string();
+    let link_options = LinkOptions {
+        respect_ignore_list: !args.include_ignored,
+    };
+    let statements = build_statements_from_local(
+        &args.source_map_base,
+        &args.url_base,
+        &project_dir,
+        &source_relocate,
+        &link_options,
+        false,
+        None,
+    )
+    .await?;
+
+    let all = collect_coverage_helper(&args.pattern, &args.filters, args.module_wrapper).await?;
+    let coverages: HashMap<String, ScriptCoverage> =
+        merge_script_coverages(all.into_values().flatten().collect())
+            .into_iter()
+            .map(|sc| (sc.url.clone(), sc))
+            .collect();
+
+    let overlay_dir = output_dir.join("overlay");
+    fs::create_dir_all(&overlay_dir).await?;
+    let mut scripts: Vec<_> = statements.iter().collect();
+    scripts.sort_by_key(|(k, _)| k.as_str());
+    let mut links = vec![];
+    for (script, statement) in scripts {
+        let code = &statement.code;
+        let tree = match coverages.get(script) {
+            Some(sc) => coverage_tree(sc),
+            None => {
+                warn!("覆盖率文件里面没有这个脚本: {}", script);
+                coverage_tree(&ScriptCoverage {
+                    url: script.clone(),
+                    source: code.to_string(),
+                    functions: vec![],
+                    script_url: statement.source_url.clone(),
+                    binary: false,
+                })
+            }
+        };
+        let sources = statement.original_sources().await;
+        let html = render_overlay(script, code, &statement.mapping, &tree, &sources);
+        let name = format!("{}.html", script);
+        fs::write(safe_join(&overlay_dir, &name)?, html).await?;
+        links.push(format!(
+            "<li><a href=\"{0}\">{1}</a></li>",
+            xml_escape(&name),
+            xml_escape(script)
+        ));
+    }
+    let index = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>overlay</title></head><body><ul>{}</ul></body></html>",
+        links.join("")
+    );
+    fs::write(overlay_dir.join("index.html"), index).await?;
+    info!("已经写入 {}", overlay_dir.to_string_lossy());
+    Ok(())
+}
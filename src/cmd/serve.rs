@@ -0,0 +1,350 @@
+use crate::format::{path_normalize, xml_escape};
+use crate::fputil::{path_to_abs, safe_join};
+use anyhow::{anyhow, Result};
+use clap::Args;
+use percent_encoding::percent_decode_str;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{debug, info, instrument, warn};
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    #[arg(long, default_value = ".")]
+    output: String, // convert 的输出目录，html 报告和源码都从这里读取
+    #[arg(long, default_value = ".nyc_output/merged.json")]
+    output_file: String, // 合并之后的覆盖率文件，相对 output，修改之后页面自动刷新
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String, // 在虚拟机里面共享给其他人访问的时候改成 0.0.0.0
+    #[arg(long, default_value_t = 8080)]
+    port: u16,
+}
+
+/// 页面轮询这个地址，返回覆盖率文件的修改时间
+const RELOAD_PATH: &str = "/__reload";
+
+/// 请求头的最大字节数，超过的时候不再继续读取
+const HEADER_LIMIT: usize = 64 * 1024;
+
+/// 读取请求头的超时时间，空闲或者很慢的连接不会一直占着
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+const RELOAD_SCRIPT: &str = r#"<script>(()=>{let v;setInterval(async()=>{try{const t=await(await fetch("/__reload")).text();if(v!==undefined&&t!==v)location.reload();v=t}catch(e){}},1000)})()</script>"#;
+
+struct ServeRoot {
+    dir: PathBuf,
+    merged_file: PathBuf,
+}
+
+#[instrument]
+pub async fn exec(args: &ServeArgs) -> Result<()> {
+    let dir = path_to_abs(&args.output)?;
+    let root = Arc::new(ServeRoot {
+        merged_file: PathBuf::from(path_normalize(
+            dir.join(&args.output_file).to_str().unwrap_or_default(),
+        )),
+        dir,
+    });
+    let listener = TcpListener::bind((args.host.as_str(), args.port)).await?;
+    info!(
+        "报告地址 http://{}，目录 {}",
+        listener.local_addr()?,
+        root.dir.to_string_lossy()
+    );
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let root = root.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &root).await {
+                debug!(peer = %addr, "处理请求出错了:{}", e);
+            }
+        });
+    }
+}
+
+/// 每个连接只处理一个请求，响应之后关闭连接
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    root: &ServeRoot,
+) -> Result<()> {
+    let buf = match tokio::time::timeout(READ_TIMEOUT, read_head(&mut stream)).await {
+        Ok(Ok(Some(buf))) => buf,
+        Ok(Ok(None)) => {
+            return write_response(&mut stream, 431, "text/plain", b"header too large").await
+        }
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return write_response(&mut stream, 408, "text/plain", b"timeout").await,
+    };
+    let head = String::from_utf8_lossy(&buf);
+    let mut parts = head.lines().next().unwrap_or_default().split(' ');
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next());
+    let Some(target) = target else {
+        return write_response(&mut stream, 400, "text/plain", b"bad request").await;
+    };
+    if method != "GET" {
+        return write_response(&mut stream, 405, "text/plain", b"method not allowed").await;
+    }
+    let (status, content_type, body) = respond(root, target).await;
+    debug!(status = status, "GET {}", target);
+    write_response(&mut stream, status, content_type, &body).await
+}
+
+/// 读到请求头结束为止，最多读 `HEADER_LIMIT` 个字节，超过的时候返回 None
+async fn read_head<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 4096];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let limit = (HEADER_LIMIT - buf.len()).min(chunk.len());
+        if limit == 0 {
+            return Ok(None);
+        }
+        let n = stream.read(&mut chunk[..limit]).await?;
+        if n == 0 {
+            return Err(anyhow!("请求还没读完连接就关闭了"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(Some(buf))
+}
+
+async fn respond(root: &ServeRoot, target: &str) -> (u16, &'static str, Vec<u8>) {
+    let path = target.split(['?', '#']).next().unwrap_or_default();
+    if path == RELOAD_PATH {
+        let version = merged_version(&root.merged_file).await;
+        return (200, "text/plain", version.to_string().into_bytes());
+    }
+    let file = match resolve(&root.dir, path) {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("拒绝访问 {}: {}", path, e);
+            return (403, "text/plain", b"forbidden".to_vec());
+        }
+    };
+    let file = if file.is_dir() {
+        let index = file.join("index.html");
+        if !index.is_file() {
+            return match directory_listing(&file, path).await {
+                Ok(html) => (200, "text/html; charset=utf-8", inject_reload(&html)),
+                Err(_) => (404, "text/plain", b"not found".to_vec()),
+            };
+        }
+        index
+    } else {
+        file
+    };
+    match fs::read(&file).await {
+        Ok(body) => {
+            let content_type = content_type(&file);
+            if content_type.starts_with("text/html") {
+                (
+                    200,
+                    content_type,
+                    inject_reload(&String::from_utf8_lossy(&body)),
+                )
+            } else {
+                (200, content_type, body)
+            }
+        }
+        Err(_) => (404, "text/plain", b"not found".to_vec()),
+    }
+}
+
+/// 把请求路径解析成 `dir` 下面的文件，不允许访问 `dir` 以外的文件
+fn resolve(dir: &Path, path: &str) -> Result<PathBuf> {
+    let path = percent_decode_str(path).decode_utf8()?;
+    let rel = path.trim_start_matches('/').trim_end_matches('/');
+    if rel.is_empty() {
+        return Ok(dir.to_path_buf());
+    }
+    safe_join(dir, rel)
+}
+
+/// 覆盖率文件的修改时间，文件不存在的时候是 0
+async fn merged_version(merged_file: &Path) -> u128 {
+    fs::metadata(merged_file)
+        .await
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
+fn inject_reload(html: &str) -> Vec<u8> {
+    match html.rfind("</body>") {
+        Some(i) => format!("{}{}{}", &html[..i], RELOAD_SCRIPT, &html[i..]),
+        None => format!("{}{}", html, RELOAD_SCRIPT),
+    }
+    .into_bytes()
+}
+
+async fn directory_listing(dir: &Path, path: &str) -> Result<String> {
+    let mut entries = vec![];
+    let mut rd = fs::read_dir(dir).await?;
+    while let Some(e) = rd.next_entry().await? {
+        let mut name = e.file_name().to_string_lossy().to_string();
+        if e.file_type().await?.is_dir() {
+            name.push('/');
+        }
+        entries.push(name);
+    }
+    entries.sort();
+    let base = format!("{}/", path.trim_end_matches('/'));
+    let items: String = entries
+        .iter()
+        .map(|n| {
+            format!(
+                "<li><a href=\"{}{}\">{}</a></li>",
+                xml_escape(&base),
+                xml_escape(n),
+                xml_escape(n)
+            )
+        })
+        .collect();
+    Ok(format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{0}</title></head><body><h1>{0}</h1><ul>{1}</ul></body></html>",
+        xml_escape(&base),
+        items
+    ))
+}
+
+fn content_type(p: &Path) -> &'static str {
+    match p.extension().and_then(|e| e.to_str()).unwrap_or_default() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" | "cjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "png" => "image/png",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "xml" => "application/xml",
+        // 源码文件直接按文本显示
+        _ => "text/plain; charset=utf-8",
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        431 => "Request Header Fields Too Large",
+        _ => "Unknown",
+    }
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    let reason = reason_phrase(status);
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let dir = Path::new("/out");
+        assert_eq!(resolve(dir, "/")?, PathBuf::from("/out"));
+        assert_eq!(
+            resolve(dir, "/src/a%20b.ts")?,
+            PathBuf::from("/out/src/a b.ts")
+        );
+        assert!(resolve(dir, "/../etc/passwd").is_err());
+        assert!(resolve(dir, "/%2e%2e/etc/passwd").is_err());
+        assert!(resolve(dir, "//etc/passwd").is_ok_and(|p| p.starts_with(dir)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_head() -> Result<()> {
+        let mut req: &[u8] = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        assert!(read_head(&mut req).await?.is_some());
+        let mut req: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(read_head(&mut req).await.is_err());
+
+        // 超过限制之后不再读取，剩下的数据留在连接里面
+        let big = vec![b'a'; HEADER_LIMIT * 2];
+        let mut req: &[u8] = &big;
+        assert!(read_head(&mut req).await?.is_none());
+        assert_eq!(req.len(), HEADER_LIMIT);
+
+        let root = ServeRoot {
+            dir: PathBuf::from("/out"),
+            merged_file: PathBuf::from("/out/merged.json"),
+        };
+        let (mut client, server) = tokio::io::duplex(HEADER_LIMIT * 2);
+        client.write_all(&big).await?;
+        handle_connection(server, &root).await?;
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await?;
+        assert!(resp.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reason_phrase() {
+        assert_eq!(reason_phrase(408), "Request Timeout");
+        assert_eq!(reason_phrase(500), "Unknown");
+    }
+
+    #[test]
+    fn test_inject_reload() {
+        let html = String::from_utf8(inject_reload("<html><body>x</body></html>")).unwrap();
+        assert!(html.ends_with("</script></body></html>"));
+        assert!(html.contains(RELOAD_PATH));
+    }
+
+    #[tokio::test]
+    async fn test_respond() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("v8-serve-{}", std::process::id()));
+        fs::create_dir_all(dir.join(".nyc_output")).await?;
+        fs::write(dir.join("index.html"), "<body>report</body>").await?;
+        let root = ServeRoot {
+            merged_file: dir.join(".nyc_output/merged.json"),
+            dir: dir.clone(),
+        };
+
+        let (status, _, body) = respond(&root, RELOAD_PATH).await;
+        assert_eq!((status, body), (200, b"0".to_vec()));
+        fs::write(&root.merged_file, "{}").await?;
+        let (_, _, body) = respond(&root, RELOAD_PATH).await;
+        assert_ne!(body, b"0".to_vec());
+
+        let (status, content_type, body) = respond(&root, "/?x=1").await;
+        assert_eq!(status, 200);
+        assert!(content_type.starts_with("text/html"));
+        assert!(String::from_utf8(body)?.contains(RELOAD_PATH));
+
+        let (status, _, _) = respond(&root, "/.nyc_output").await;
+        assert_eq!(status, 200);
+        let (status, _, _) = respond(&root, "/missing.js").await;
+        assert_eq!(status, 404);
+        let (status, _, _) = respond(&root, "/../x").await;
+        assert_eq!(status, 403);
+
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
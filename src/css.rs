@@ -0,0 +1,344 @@
+use crate::format::istanbul::{IstanbulCov, Position, StatementMap};
+use crate::format::path_normalize;
+use crate::format::script_coverage::{url_filename, url_matches_filters, CountMode};
+use crate::fputil::{get_uri_resource, glob_abs};
+use crate::statement::{
+    decode_source_map, join_uri, relocate_sources, source_map_from_file, url_normalize,
+};
+use crate::timer::Timer;
+use crate::translate::{line_offsets, LinkOptions, SourceFilter};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::Deserialize;
+use sourcemap::SourceMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::OnceLock;
+use tokio::fs;
+use tracing::{debug, info, instrument, warn};
+
+/// Playwright `coverage.stopCSSCoverage()` / Puppeteer 输出的样式表覆盖率
+#[derive(Debug, Deserialize, Clone)]
+pub struct CssCoverage {
+    pub url: String,
+    pub text: String,
+    pub ranges: Vec<CssRange>,
+}
+
+/// 被使用的区间，offset 按字符计算，end 不包含
+#[derive(Debug, Deserialize, Clone)]
+pub struct CssRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+/// 生成代码里面的一条 css 规则，从选择器开始到 `}` 结束，end 不包含
+#[derive(Debug, Clone, PartialEq)]
+pub struct CssRule {
+    pub start: u32,
+    pub end: u32,
+}
+
+/// 找出样式表里面的规则
+///
+/// `@media`、`@supports` 这种包含其他规则的 at-rule 本身不算规则，
+/// `@font-face` 这种只有声明的 at-rule 算一条规则
+pub fn parse_css_rules(text: &str) -> Vec<CssRule> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut rules = vec![];
+    // (选择器开始位置, 是否 at-rule, 是否包含子规则)
+    let mut stack: Vec<(usize, bool, bool)> = vec![];
+    let mut selector_start: Option<usize> = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            '"' | '\'' => {
+                selector_start.get_or_insert(i);
+                i += 1;
+                while i < chars.len() && chars[i] != c {
+                    if chars[i] == '\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            '{' => {
+                if let Some(parent) = stack.last_mut() {
+                    parent.2 = true;
+                }
+                let start = selector_start.take().unwrap_or(i);
+                stack.push((start, chars[start] == '@', false));
+            }
+            '}' => {
+                if let Some((start, at_rule, has_child)) = stack.pop() {
+                    if !at_rule || !has_child {
+                        rules.push(CssRule {
+                            start: start as u32,
+                            end: i as u32 + 1,
+                        });
+                    }
+                }
+                selector_start = None;
+            }
+            ';' => selector_start = None,
+            c if c.is_whitespace() => {}
+            _ => {
+                selector_start.get_or_insert(i);
+            }
+        }
+        i += 1;
+    }
+    rules.sort_by_key(|r| r.start);
+    rules
+}
+
+fn source_mapping_url_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"/\*\s*[#@]\s*sourceMappingURL=(\S+?)\s*\*/").unwrap())
+}
+
+/// 和 js 的覆盖率一样按 `--filters` 过滤样式表
+#[instrument]
+pub async fn collect_css_coverage(
+    pattern: &str,
+    filters: &[String],
+) -> Result<HashMap<String, Vec<CssCoverage>>> {
+    let _timer = Timer::new("收集本地 css 覆盖率数据");
+    let mut r = HashMap::new();
+    for p in glob_abs(pattern)? {
+        debug!("处理 css 覆盖率文件 {}", p.to_string_lossy());
+        let s = fs::read_to_string(&p).await?;
+        let mut arr: Vec<CssCoverage> =
+            serde_json::from_str(&s).map_err(|e| anyhow!("解析{:?}出错, {}", p.to_str(), e))?;
+        arr.retain(|c| url_matches_filters(&url_normalize(&c.url), filters));
+        r.insert(p.to_string_lossy().to_string(), arr);
+    }
+    Ok(r)
+}
+
+/// 本地的 css source map，key 是对应的 css 文件名
+#[instrument(skip(source_relocate))]
+pub async fn build_css_source_maps(
+    source_map_pattern: &str,
+    source_relocate: &Option<(Regex, String)>,
+) -> Result<HashMap<String, SourceMap>> {
+    let mut r = HashMap::new();
+    for p in glob_abs(source_map_pattern)? {
+        let name = p.to_string_lossy().to_string();
+        let Some(css_name) = name.strip_suffix(".map").filter(|n| n.ends_with(".css")) else {
+            continue;
+        };
+        let sm = source_map_from_file(&name, source_relocate).await?;
+        let key = sm
+            .get_file()
+            .map(url_filename)
+            .unwrap_or_else(|| url_filename(css_name));
+        info!(file = key, "css source map");
+        r.insert(key, sm);
+    }
+    Ok(r)
+}
+
+/// 本地没有对应的 source map 的时候，按 `sourceMappingURL` 注释获取
+pub async fn css_source_map(
+    cov: &CssCoverage,
+    local: &HashMap<String, SourceMap>,
+    source_relocate: &Option<(Regex, String)>,
+) -> Result<Option<SourceMap>> {
+    if let Some(sm) = local.get(&url_filename(&cov.url)) {
+        return Ok(Some(sm.clone()));
+    }
+    let Some(cap) = source_mapping_url_regex().captures_iter(&cov.text).last() else {
+        return Ok(None);
+    };
+    let uri = join_uri(&cov.url, &cap[1]);
+    let s = get_uri_resource(&uri).await?;
+    let mut sm = decode_source_map(s.as_bytes(), &uri).await?;
+    relocate_sources(&mut sm, source_relocate);
+    Ok(Some(sm))
+}
+
+/// 源码规则开始位置 -> (结束位置, 执行次数)
+type RuleCounts = BTreeMap<(u32, u32), ((u32, u32), u32)>;
+
+/// 把生成代码里面的规则映射回源码，每条源码规则生成一个 istanbul 语句
+#[instrument(skip_all, fields(url = cov.url))]
+pub fn css_to_istanbul(
+    cov: &CssCoverage,
+    sm: &SourceMap,
+    code_dir: &str,
+    options: &LinkOptions,
+) -> HashMap<String, IstanbulCov> {
+    let filter = SourceFilter::new(sm, options);
+    let sect = line_offsets(&cov.text);
+    let position = |offset: u32| -> (u32, u32) {
+        let line = sect.partition_point(|&s| s <= offset).saturating_sub(1);
+        (line as u32, offset - sect[line])
+    };
+
+    let mut rules: HashMap<String, RuleCounts> = HashMap::new();
+    for rule in parse_css_rules(&cov.text) {
+        let used = cov
+            .ranges
+            .iter()
+            .any(|r| r.start < rule.end && rule.start < r.end);
+        let (line, col) = position(rule.start);
+        let Some(token) = sm.lookup_token(line, col) else {
+            continue;
+        };
+        let (Some(source), src_id) = (token.get_source(), token.get_src_id()) else {
+            continue;
+        };
+        if !filter.allow(src_id, source) {
+            continue;
+        }
+        let start = (token.get_src_line(), token.get_src_col());
+        let (line, col) = position(rule.end - 1);
+        let end = sm
+            .lookup_token(line, col)
+            .filter(|t| t.get_src_id() == src_id)
+            .map(|t| (t.get_src_line(), t.get_src_col() + 1))
+            .filter(|end| *end > start)
+            .unwrap_or((start.0, start.1 + 1));
+        let e = rules
+            .entry(source.to_string())
+            .or_default()
+            .entry(start)
+            .or_insert((end, 0));
+        e.1 += used as u32;
+    }
+
+    let base = Path::new(code_dir);
+    let mut r = HashMap::new();
+    for (source, rules) in rules {
+        let path = path_normalize(base.join(&source).to_str().unwrap_or_default());
+        let mut ic = IstanbulCov {
+            path: path.clone(),
+            // 样式规则只有用过和没用过
+            count_mode: Some(CountMode::Binary),
+            ..Default::default()
+        };
+        for (i, (start, (end, count))) in rules.into_iter().enumerate() {
+            ic.statement_map.insert(
+                i.to_string(),
+                StatementMap {
+                    start: Position {
+                        line: start.0 + 1,
+                        column: start.1,
+                    },
+                    end: Position {
+                        line: end.0 + 1,
+                        column: end.1,
+                    },
+                    skip: None,
+                },
+            );
+            ic.s.insert(i.to_string(), count);
+        }
+        r.insert(path, ic);
+    }
+    r
+}
+
+/// 处理一个样式表的覆盖率，找不到 source map 的样式表会被跳过
+pub async fn handle_css_coverage(
+    cov: &CssCoverage,
+    local: &HashMap<String, SourceMap>,
+    source_relocate: &Option<(Regex, String)>,
+    code_dir: &str,
+    options: &LinkOptions,
+) -> Result<HashMap<String, IstanbulCov>> {
+    match css_source_map(cov, local, source_relocate).await? {
+        Some(sm) => Ok(css_to_istanbul(cov, &sm, code_dir, options)),
+        None => {
+            warn!("样式表没有 source map，已跳过: {}", cov.url);
+            Ok(HashMap::new())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_css_rules() {
+        let text = r#"a{color:red}
+/* b{} */
+@media (max-width: 10px) { .c { content: "}" } .d{} }
+@font-face{font-family:x}
+"#;
+        let rules: Vec<&str> = parse_css_rules(text)
+            .iter()
+            .map(|r| &text[r.start as usize..r.end as usize])
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                "a{color:red}",
+                r#".c { content: "}" }"#,
+                ".d{}",
+                "@font-face{font-family:x}"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_collect_css_coverage() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("v8-css-coverage-{}", std::process::id()));
+        fs::create_dir_all(&dir).await?;
+        fs::write(
+            dir.join("css.json"),
+            r#"[{"url":"http://a/app.css","text":"a{}","ranges":[]},{"url":"http://a/vendor.css","text":"b{}","ranges":[]}]"#,
+        )
+        .await?;
+        let pattern = dir.join("*.json").to_string_lossy().to_string();
+        let all = collect_css_coverage(&pattern, &[]).await?;
+        assert_eq!(all.values().flatten().count(), 2);
+        let r = collect_css_coverage(&pattern, &["app.css".to_string()]).await?;
+        let urls: Vec<&str> = r.values().flatten().map(|c| c.url.as_str()).collect();
+        assert_eq!(urls, vec!["http://a/app.css"]);
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_css_to_istanbul() -> Result<()> {
+        let text = include_str!("../tests/jsx/main.4fb9715b.chunk.css");
+        let local = build_css_source_maps(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/jsx/*.map"),
+            &None,
+        )
+        .await?;
+        assert_eq!(local.len(), 1);
+
+        let cov = CssCoverage {
+            url: "http://localhost:3000/main.4fb9715b.chunk.css".to_string(),
+            text: text.to_string(),
+            ranges: vec![CssRange { start: 0, end: 26 }],
+        };
+        let sm = css_source_map(&cov, &local, &None).await?.unwrap();
+        let r = css_to_istanbul(&cov, &sm, "/p", &LinkOptions::default());
+        let ic = &r["/p/src/Hello.module.less"];
+        assert_eq!(ic.s["0"], 1);
+        assert_eq!(ic.statement_map["0"].start.line, 1);
+        assert_eq!(ic.statement_map["0"].start.column, 0);
+        assert_eq!(ic.statement_map["0"].end.line, 3);
+
+        let unused = CssCoverage {
+            ranges: vec![],
+            ..cov
+        };
+        let r = css_to_istanbul(&unused, &sm, "/p", &LinkOptions::default());
+        assert_eq!(r["/p/src/Hello.module.less"].s["0"], 0);
+        Ok(())
+    }
+}
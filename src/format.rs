@@ -1,8 +1,22 @@
+use crate::traverse::CodeSpan;
 use serde::{Deserialize, Serialize};
+use std::path::Path;
 
+pub mod clover;
+pub mod codecov;
+pub mod coveralls;
+pub mod input;
 pub mod istanbul;
+pub mod layout;
 mod lcov;
+pub mod line;
+pub mod overlay;
+pub mod report;
 pub mod script_coverage;
+pub mod sonar;
+pub mod summary;
+pub mod text;
+pub mod v8_merge;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MappingItem {
@@ -23,6 +37,29 @@ pub struct MappingItem {
     #[serde(rename = "c")]
     pub count: u32,
     pub idx: usize,
+    /// 被 ignore 注释排除的语句，报告里面标记成 skip
+    #[serde(rename = "k", default, skip_serializing_if = "std::ops::Not::not")]
+    pub skip: bool,
+}
+
+/// source map 里面带有 name 的 token，用来还原压缩后的函数名
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NameItem {
+    #[serde(rename = "gs")]
+    pub generated_column: u32,
+    #[serde(rename = "n")]
+    pub name: String,
+}
+
+/// v8 的函数覆盖率映射到源码之后的结果
+#[derive(Debug, Clone)]
+pub struct FunctionItem {
+    pub source: String,
+    pub name: String,
+    pub decl: CodeSpan,
+    pub loc: CodeSpan,
+    pub count: u32,
+    pub skip: bool,
 }
 
 // pub fn source_map_key(source_map: &SourceMap) -> String {
@@ -59,3 +96,30 @@ pub fn path_normalize(path: &str) -> String {
     }
     r.join("/")
 }
+
+/// 相对 `root` 的路径，统一使用 `/` 分隔，不在 `root` 下面的路径原样返回
+pub fn relative_path(path: &str, root: &Path) -> String {
+    match Path::new(path).strip_prefix(root) {
+        Ok(p) => p
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+        Err(_) => path.to_string(),
+    }
+}
+
+pub fn xml_escape(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => r.push_str("&amp;"),
+            '<' => r.push_str("&lt;"),
+            '>' => r.push_str("&gt;"),
+            '"' => r.push_str("&quot;"),
+            '\'' => r.push_str("&apos;"),
+            _ => r.push(c),
+        }
+    }
+    r
+}
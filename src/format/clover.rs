@@ -0,0 +1,202 @@
+use crate::format::istanbul::IstanbulCov;
+use crate::format::line::{branch_coverage, line_coverage, LineMode};
+use crate::format::summary::CoverageSummary;
+use crate::format::{relative_path, xml_escape};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+fn metrics(s: &CoverageSummary) -> String {
+    // 和 istanbul 的 clover reporter 一样，statements 用的是行覆盖率
+    format!(
+        "statements=\"{}\" coveredstatements=\"{}\" conditionals=\"{}\" coveredconditionals=\"{}\" methods=\"{}\" coveredmethods=\"{}\"",
+        s.lines.total,
+        s.lines.covered,
+        s.branches.total,
+        s.branches.covered,
+        s.functions.total,
+        s.functions.covered
+    )
+}
+
+fn write_file(out: &mut String, path: &str, ic: &IstanbulCov, mode: LineMode) {
+    let summary = CoverageSummary::from(ic, mode);
+    let name = Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or(path.to_string());
+    writeln!(
+        out,
+        "      <file name=\"{}\" path=\"{}\">",
+        xml_escape(&name),
+        xml_escape(path)
+    )
+    .unwrap();
+    writeln!(out, "        <metrics {}/>", metrics(&summary)).unwrap();
+
+    // 同一行先输出函数，再输出语句或者分支
+    let mut methods: BTreeMap<u32, Vec<(String, u32)>> = BTreeMap::new();
+    for (key, f) in ic.fn_map.iter() {
+        methods
+            .entry(f.line.max(1) as u32)
+            .or_default()
+            .push((f.name.clone(), ic.f.get(key).copied().unwrap_or_default()));
+    }
+    let lines = line_coverage(ic, mode);
+    let branches = branch_coverage(ic);
+    let mut nums: Vec<u32> = methods.keys().chain(lines.keys()).copied().collect();
+    nums.sort();
+    nums.dedup();
+    for num in nums {
+        for (name, count) in methods.get(&num).into_iter().flatten() {
+            writeln!(
+                out,
+                "        <line num=\"{}\" count=\"{}\" type=\"method\" signature=\"{}\"/>",
+                num,
+                count,
+                xml_escape(name)
+            )
+            .unwrap();
+        }
+        let Some(count) = lines.get(&num) else {
+            continue;
+        };
+        match branches.get(&num) {
+            Some((total, covered)) if *total > 0 => writeln!(
+                out,
+                "        <line num=\"{}\" count=\"{}\" type=\"cond\" truecount=\"{}\" falsecount=\"{}\"/>",
+                num,
+                count,
+                covered,
+                total - covered
+            ),
+            _ => writeln!(
+                out,
+                "        <line num=\"{}\" count=\"{}\" type=\"stmt\"/>",
+                num, count
+            ),
+        }
+        .unwrap();
+    }
+    out.push_str("      </file>\n");
+}
+
+/// Clover XML 报告，按照相对 `project_root` 的目录分 package
+pub fn render_clover(
+    merged: &BTreeMap<&str, &IstanbulCov>,
+    project_root: &Path,
+    mode: LineMode,
+    timestamp: u128,
+) -> String {
+    let mut packages: BTreeMap<String, Vec<(&str, &IstanbulCov)>> = BTreeMap::new();
+    let mut total = CoverageSummary::default();
+    for (path, ic) in merged {
+        total.merge(&CoverageSummary::from(ic, mode));
+        let rel = relative_path(path, project_root);
+        let package = match rel.rsplit_once('/') {
+            Some((dir, _)) => dir.replace('/', "."),
+            None => "root".to_string(),
+        };
+        packages.entry(package).or_default().push((path, ic));
+    }
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        out,
+        "<coverage generated=\"{}\" clover=\"3.2.0\">",
+        timestamp
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "  <project timestamp=\"{}\" name=\"All files\">",
+        timestamp
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "    <metrics {} elements=\"{}\" coveredelements=\"{}\" complexity=\"0\" loc=\"{}\" ncloc=\"{}\" packages=\"{}\" files=\"{}\" classes=\"{}\"/>",
+        metrics(&total),
+        total.lines.total + total.branches.total + total.functions.total,
+        total.lines.covered + total.branches.covered + total.functions.covered,
+        total.lines.total,
+        total.lines.total,
+        packages.len(),
+        merged.len(),
+        merged.len()
+    )
+    .unwrap();
+    for (package, files) in packages {
+        let mut summary = CoverageSummary::default();
+        for (_, ic) in files.iter() {
+            summary.merge(&CoverageSummary::from(ic, mode));
+        }
+        writeln!(out, "    <package name=\"{}\">", xml_escape(&package)).unwrap();
+        writeln!(out, "      <metrics {}/>", metrics(&summary)).unwrap();
+        for (path, ic) in files {
+            write_file(&mut out, path, ic, mode);
+        }
+        out.push_str("    </package>\n");
+    }
+    out.push_str("  </project>\n</coverage>\n");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::istanbul::{BranchMap, FnMap, Location, Position, StatementMap};
+
+    #[test]
+    fn test_render_clover() {
+        let pos = |line| Position { line, column: 0 };
+        let mut ic = IstanbulCov::default();
+        for (key, line, count) in [("0", 2, 1), ("1", 3, 1), ("2", 5, 0)] {
+            ic.statement_map.insert(
+                key.to_string(),
+                StatementMap {
+                    start: pos(line),
+                    end: pos(line),
+                    skip: None,
+                },
+            );
+            ic.s.insert(key.to_string(), count);
+        }
+        ic.fn_map.insert(
+            "0".to_string(),
+            FnMap {
+                name: "foo".to_string(),
+                line: 1,
+                loc: Location {
+                    start: pos(1),
+                    end: pos(6),
+                    skip: None,
+                },
+                decl: None,
+                skip: None,
+            },
+        );
+        ic.f.insert("0".to_string(), 1);
+        ic.branch_map.insert(
+            "0".to_string(),
+            BranchMap {
+                line: 3,
+                r#type: "if".to_string(),
+                locations: vec![],
+            },
+        );
+        ic.b.insert("0".to_string(), vec![1, 0]);
+        let merged = BTreeMap::from([("/p/src/lib/a.js", &ic)]);
+        let xml = render_clover(&merged, Path::new("/p"), LineMode::Max, 1);
+        assert!(xml.contains(r#"<package name="src.lib">"#));
+        assert!(xml.contains(r#"<file name="a.js" path="/p/src/lib/a.js">"#));
+        assert!(xml.contains(r#"<metrics statements="3" coveredstatements="2" conditionals="2" coveredconditionals="1" methods="1" coveredmethods="1"/>"#));
+        assert!(xml.contains(r#"elements="6" coveredelements="4""#));
+        assert!(xml.contains(r#"<line num="1" count="1" type="method" signature="foo"/>"#));
+        assert!(xml.contains(r#"<line num="2" count="1" type="stmt"/>"#));
+        assert!(
+            xml.contains(r#"<line num="3" count="1" type="cond" truecount="1" falsecount="1"/>"#)
+        );
+        assert!(xml.contains(r#"<line num="5" count="0" type="stmt"/>"#));
+    }
+}
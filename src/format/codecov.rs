@@ -0,0 +1,84 @@
+use crate::format::istanbul::IstanbulCov;
+use crate::format::line::{branch_coverage, line_coverage, LineMode};
+use crate::format::relative_path;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// 行覆盖率，分支没有全部覆盖的行用 `"覆盖数/总数"` 表示
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum CodecovHits {
+    Hits(u32),
+    Partial(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct CodecovReport {
+    pub coverage: BTreeMap<String, BTreeMap<String, CodecovHits>>,
+}
+
+/// Codecov 的 JSON 覆盖率格式 `{"coverage": {file: {line: hits}}}`
+pub fn render_codecov(
+    merged: &BTreeMap<&str, &IstanbulCov>,
+    project_root: &Path,
+    mode: LineMode,
+) -> CodecovReport {
+    let mut coverage = BTreeMap::new();
+    for (path, ic) in merged {
+        let branches = branch_coverage(ic);
+        let lines = line_coverage(ic, mode)
+            .into_iter()
+            .map(|(line, count)| {
+                let hits = match branches.get(&line) {
+                    Some((total, covered)) if count > 0 && covered < total => {
+                        CodecovHits::Partial(format!("{}/{}", covered, total))
+                    }
+                    _ => CodecovHits::Hits(count),
+                };
+                (line.to_string(), hits)
+            })
+            .collect();
+        coverage.insert(relative_path(path, project_root), lines);
+    }
+    CodecovReport { coverage }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::istanbul::{BranchMap, Position, StatementMap};
+
+    #[test]
+    fn test_render_codecov() {
+        let mut ic = IstanbulCov::default();
+        for (key, line, count) in [("0", 1, 2), ("1", 2, 1), ("2", 3, 0)] {
+            let pos = Position { line, column: 0 };
+            ic.statement_map.insert(
+                key.to_string(),
+                StatementMap {
+                    start: pos.clone(),
+                    end: pos,
+                    skip: None,
+                },
+            );
+            ic.s.insert(key.to_string(), count);
+        }
+        ic.branch_map.insert(
+            "0".to_string(),
+            BranchMap {
+                line: 2,
+                r#type: "if".to_string(),
+                locations: vec![],
+            },
+        );
+        ic.b.insert("0".to_string(), vec![1, 0]);
+        let merged = BTreeMap::from([("/p/a.js", &ic)]);
+        let json =
+            serde_json::to_value(render_codecov(&merged, Path::new("/p"), LineMode::Max)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"coverage": {"a.js": {"1": 2, "2": "1/2", "3": 0}}})
+        );
+    }
+}
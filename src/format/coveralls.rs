@@ -0,0 +1,122 @@
+use crate::format::istanbul::IstanbulCov;
+use crate::format::line::{line_coverage, LineMode};
+use crate::format::relative_path;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CoverallsSourceFile {
+    pub name: String,
+    /// 源码的 md5
+    pub source_digest: String,
+    /// 下标是行号减一，不需要统计的行是 null
+    pub coverage: Vec<Option<u32>>,
+    /// 每 4 个数字一组：行号、分支块编号、块内分支编号、执行次数
+    pub branches: Vec<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CoverallsPayload {
+    pub source_files: Vec<CoverallsSourceFile>,
+}
+
+pub fn coveralls_source_file(
+    name: String,
+    source: &str,
+    ic: &IstanbulCov,
+    mode: LineMode,
+) -> CoverallsSourceFile {
+    let lines = line_coverage(ic, mode);
+    let line_count = source
+        .split('\n')
+        .count()
+        .max(lines.keys().last().copied().unwrap_or_default() as usize);
+    let mut coverage = vec![None; line_count];
+    for (line, count) in lines {
+        coverage[line as usize - 1] = Some(count);
+    }
+
+    let mut blocks: Vec<(&String, u32)> = ic
+        .branch_map
+        .iter()
+        .map(|(k, b)| (k, b.line.max(1) as u32))
+        .collect();
+    blocks.sort_by_key(|(k, line)| (*line, k.parse::<u32>().unwrap_or_default()));
+    let mut branches = vec![];
+    for (block, (key, line)) in blocks.into_iter().enumerate() {
+        for (branch, count) in ic.b.get(key).into_iter().flatten().enumerate() {
+            branches.extend([line, block as u32, branch as u32, *count]);
+        }
+    }
+
+    CoverallsSourceFile {
+        name,
+        source_digest: format!("{:x}", md5::compute(source.as_bytes())),
+        coverage,
+        branches,
+    }
+}
+
+/// Coveralls 的 `source_files` 数据，`sources` 里面没有源码的文件会被跳过
+pub fn render_coveralls(
+    merged: &BTreeMap<&str, &IstanbulCov>,
+    sources: &BTreeMap<String, String>,
+    project_root: &Path,
+    mode: LineMode,
+) -> CoverallsPayload {
+    CoverallsPayload {
+        source_files: merged
+            .iter()
+            .filter_map(|(path, ic)| {
+                sources.get(*path).map(|source| {
+                    coveralls_source_file(relative_path(path, project_root), source, ic, mode)
+                })
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::istanbul::{BranchMap, Position, StatementMap};
+
+    #[test]
+    fn test_coveralls_source_file() {
+        let mut ic = IstanbulCov::default();
+        for (key, line, count) in [("0", 1, 2), ("1", 3, 0)] {
+            let pos = Position { line, column: 0 };
+            ic.statement_map.insert(
+                key.to_string(),
+                StatementMap {
+                    start: pos.clone(),
+                    end: pos,
+                    skip: None,
+                },
+            );
+            ic.s.insert(key.to_string(), count);
+        }
+        ic.branch_map.insert(
+            "0".to_string(),
+            BranchMap {
+                line: 3,
+                r#type: "if".to_string(),
+                locations: vec![],
+            },
+        );
+        ic.b.insert("0".to_string(), vec![0, 1]);
+        let f = coveralls_source_file("a.js".to_string(), "a\n\nb\n", &ic, LineMode::Max);
+        assert_eq!(
+            f,
+            CoverallsSourceFile {
+                name: "a.js".to_string(),
+                source_digest: "3b89023ba6b3412d0f14ed0ae351b868".to_string(),
+                coverage: vec![Some(2), None, Some(0), None],
+                branches: vec![3, 0, 0, 0, 3, 0, 1, 1],
+            }
+        );
+        let json = serde_json::to_string(&f.coverage).unwrap();
+        assert_eq!(json, "[2,null,0,null]");
+    }
+}
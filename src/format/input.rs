@@ -0,0 +1,213 @@
+use crate::format::script_coverage::{CoverageRange, FunctionCoverage, ScriptCoverageRaw};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// 覆盖率文件的格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    /// Playwright `coverage.stopJSCoverage()`，原始的 v8 `[{url, scriptId, source, functions}]`
+    V8,
+    /// Puppeteer `page.coverage.stopJSCoverage()`，已经展开成使用过的区间 `[{url, text, ranges}]`
+    Puppeteer,
+    /// CDP `Profiler.takePreciseCoverage` 的返回值 `{result: [...]}`
+    Cdp,
+    /// `NODE_V8_COVERAGE` 目录下的文件，`{result: [...], "source-map-cache": {...}}`
+    Node,
+}
+
+#[derive(Debug, Deserialize)]
+struct FlatRange {
+    start: u32,
+    end: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct FlatCoverage {
+    url: String,
+    text: String,
+    ranges: Vec<FlatRange>,
+}
+
+impl From<FlatCoverage> for ScriptCoverageRaw {
+    /// 展开的区间没有执行次数，用一个覆盖全文件的未执行区间加上执行过的区间来表示，计数只有 0 / 1
+    fn from(c: FlatCoverage) -> Self {
+        let mut ranges = vec![CoverageRange {
+            start_offset: 0,
+            end_offset: c.text.chars().count() as u32,
+            count: 0,
+        }];
+        ranges.extend(c.ranges.iter().map(|r| CoverageRange {
+            start_offset: r.start,
+            end_offset: r.end,
+            count: 1,
+        }));
+        ScriptCoverageRaw {
+            url: c.url,
+            source: Some(c.text),
+            functions: vec![FunctionCoverage {
+                function_name: "".to_string(),
+                ranges,
+                is_block_coverage: true,
+            }],
+            binary: true,
+        }
+    }
+}
+
+fn keys(v: &Value) -> String {
+    match v.as_object() {
+        Some(o) => o.keys().cloned().collect::<Vec<_>>().join(", "),
+        None => format!("{}", v).chars().take(50).collect(),
+    }
+}
+
+fn parse_entries(arr: Vec<Value>) -> Result<(InputFormat, Vec<ScriptCoverageRaw>)> {
+    let Some(first) = arr.first() else {
+        return Ok((InputFormat::V8, vec![]));
+    };
+    let format = if first.get("functions").is_some() {
+        InputFormat::V8
+    } else if first.get("ranges").is_some() && first.get("text").is_some() {
+        InputFormat::Puppeteer
+    } else {
+        return Err(anyhow!(
+            "无法识别的覆盖率格式，数组元素需要有 functions（v8）或者 text + ranges（puppeteer）字段，实际字段: [{}]",
+            keys(first)
+        ));
+    };
+    let mut r = vec![];
+    for (i, mut v) in arr.into_iter().enumerate() {
+        let sc = match format {
+            InputFormat::Puppeteer => match v.get_mut("rawScriptCoverage").map(Value::take) {
+                // puppeteer 开启 includeRawScriptCoverage 之后带有原始的 v8 覆盖率
+                Some(raw) if !raw.is_null() => {
+                    let mut sc: ScriptCoverageRaw = serde_json::from_value(raw)
+                        .map_err(|e| anyhow!("第 {} 个 rawScriptCoverage 格式错误: {}", i, e))?;
+                    sc.source = sc
+                        .source
+                        .or(v.get("text").and_then(|t| t.as_str().map(String::from)));
+                    sc
+                }
+                _ => serde_json::from_value::<FlatCoverage>(v)
+                    .map_err(|e| anyhow!("第 {} 个 puppeteer 覆盖率格式错误: {}", i, e))?
+                    .into(),
+            },
+            _ => serde_json::from_value(v)
+                .map_err(|e| anyhow!("第 {} 个 v8 覆盖率格式错误: {}", i, e))?,
+        };
+        r.push(sc);
+    }
+    Ok((format, r))
+}
+
+/// 自动识别覆盖率文件的格式，统一转换成 v8 的 `ScriptCoverageRaw`
+pub fn parse_coverage_input(s: &str) -> Result<(InputFormat, Vec<ScriptCoverageRaw>)> {
+    let v: Value = serde_json::from_str(s).map_err(|e| anyhow!("不是合法的 JSON: {}", e))?;
+    match v {
+        Value::Array(arr) => parse_entries(arr),
+        Value::Object(mut o) => {
+            let format = if o.contains_key("source-map-cache") {
+                InputFormat::Node
+            } else {
+                InputFormat::Cdp
+            };
+            let result = match o.remove("result") {
+                // CDP 的 JSON-RPC 消息 `{id, result: {result: [...]}}`
+                Some(Value::Object(mut inner)) => inner.remove("result"),
+                r => r,
+            };
+            match result {
+                Some(Value::Array(arr)) => {
+                    let (_, r) = parse_entries(arr)?;
+                    Ok((format, r))
+                }
+                _ => Err(anyhow!(
+                    "无法识别的覆盖率格式，对象需要有 result 数组（CDP / node），实际字段: [{}]",
+                    o.keys().cloned().collect::<Vec<_>>().join(", ")
+                )),
+            }
+        }
+        v => Err(anyhow!(
+            "无法识别的覆盖率格式，需要数组或者对象: {}",
+            keys(&v)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const V8: &str = r#"{"url":"http://a/main.js","scriptId":"1","source":"f()","functions":[{"functionName":"","ranges":[{"startOffset":0,"endOffset":3,"count":1}],"isBlockCoverage":false}]}"#;
+
+    #[test]
+    fn test_detect_v8() -> Result<()> {
+        let (f, r) = parse_coverage_input(&format!("[{}]", V8))?;
+        assert_eq!(f, InputFormat::V8);
+        assert_eq!(r[0].source.as_deref(), Some("f()"));
+        assert_eq!(r[0].functions.len(), 1);
+
+        let (f, r) = parse_coverage_input(&format!(r#"{{"result":[{}],"timestamp":1}}"#, V8))?;
+        assert_eq!(f, InputFormat::Cdp);
+        assert_eq!(r.len(), 1);
+
+        let (f, r) =
+            parse_coverage_input(&format!(r#"{{"id":3,"result":{{"result":[{}]}}}}"#, V8))?;
+        assert_eq!(f, InputFormat::Cdp);
+        assert_eq!(r.len(), 1);
+
+        let (f, r) =
+            parse_coverage_input(&format!(r#"{{"result":[{}],"source-map-cache":{{}}}}"#, V8))?;
+        assert_eq!(f, InputFormat::Node);
+        assert_eq!(r[0].url, "http://a/main.js");
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect_puppeteer() -> Result<()> {
+        let (f, r) = parse_coverage_input(
+            r#"[{"url":"http://a/main.js","text":"a();b();","ranges":[{"start":0,"end":4}]}]"#,
+        )?;
+        assert_eq!(f, InputFormat::Puppeteer);
+        // 展开之后的区间没有执行次数
+        assert!(r[0].binary);
+        let ranges = &r[0].functions[0].ranges;
+        assert_eq!(
+            (
+                ranges[0].start_offset,
+                ranges[0].end_offset,
+                ranges[0].count
+            ),
+            (0, 8, 0)
+        );
+        assert_eq!(
+            (
+                ranges[1].start_offset,
+                ranges[1].end_offset,
+                ranges[1].count
+            ),
+            (0, 4, 1)
+        );
+
+        let (f, r) = parse_coverage_input(&format!(
+            r#"[{{"url":"http://a/main.js","text":"f()","ranges":[],"rawScriptCoverage":{}}}]"#,
+            V8
+        ))?;
+        assert_eq!(f, InputFormat::Puppeteer);
+        assert_eq!(r[0].functions[0].ranges[0].count, 1);
+        assert!(!r[0].binary);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_format() {
+        let e = parse_coverage_input(r#"[{"url":"a.js"}]"#).unwrap_err();
+        assert!(e.to_string().contains("url"));
+        assert!(parse_coverage_input(r#"{"data":[]}"#).is_err());
+        assert!(parse_coverage_input("1").is_err());
+        assert!(parse_coverage_input("[").is_err());
+        let e = parse_coverage_input(r#"[{"url":"a.js","functions":1}]"#).unwrap_err();
+        assert!(e.to_string().contains("第 0 个"));
+    }
+}
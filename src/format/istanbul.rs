@@ -1,13 +1,19 @@
-use crate::format::{path_normalize, MappingItem};
+use crate::format::script_coverage::{
+    find_root_value_only, CountMode, CoverRangeNodeRead, CoverageRange,
+};
+use crate::format::{path_normalize, FunctionItem, MappingItem};
+use crate::fputil::{hash, safe_join};
+use crate::translate::{LinkOptions, SourceFilter};
+use crate::traverse::{CodeSpan, ParsedCode};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use sourcemap::SourceMap;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 use tokio::fs;
 use tracing::{instrument, warn};
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct IstanbulCov {
     pub path: String,
     #[serde(rename = "statementMap")]
@@ -19,79 +25,234 @@ pub struct IstanbulCov {
     #[serde(rename = "fnMap")]
     pub fn_map: HashMap<String, FnMap>,
     pub f: HashMap<String, u32>,
+    /// 旧版本 istanbul 的行覆盖率，只有开启 `--emit-lines` 才会输出
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub l: Option<HashMap<String, u32>>,
+    /// 计数的含义，精确的块级覆盖率还是只表示有没有执行过
+    #[serde(rename = "countMode", default, skip_serializing_if = "Option::is_none")]
+    pub count_mode: Option<CountMode>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Location {
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Location {
     pub start: Position,
     pub end: Position,
+    /// 分支的 locations 用这个字段标记被 ignore 注释排除的分支
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skip: Option<bool>,
 }
-#[derive(Debug, Serialize, Deserialize)]
-struct Position {
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Position {
     pub line: u32,
     pub column: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct FnMap {
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FnMap {
     pub name: String,
     pub line: i32,
     pub loc: Location,
     pub decl: Option<Location>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skip: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct BranchMap {
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BranchMap {
     pub line: i32,
     #[serde(rename = "type")]
     pub r#type: String,
     pub locations: Vec<Location>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct StatementMap {
-    start: Position,
-    end: Position,
+    pub start: Position,
+    pub end: Position,
+    /// 被 ignore 注释排除的语句，和 istanbul 一样只在排除的时候输出
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skip: Option<bool>,
+}
+
+impl IstanbulCov {
+    /// 合并同一个文件的覆盖率，计数累加
+    pub fn merge(&mut self, other: IstanbulCov) {
+        self.count_mode = match (self.count_mode, other.count_mode) {
+            (Some(a), Some(b)) if a != b => {
+                warn!(
+                    path = other.path,
+                    "合并了不同精度的覆盖率 {:?} / {:?}，计数按精度较低的 {:?} 处理",
+                    a,
+                    b,
+                    a.min(b)
+                );
+                Some(a.min(b))
+            }
+            (a, b) => a.or(b),
+        };
+        self.path = other.path;
+        for (index, s) in other.statement_map {
+            self.statement_map.insert(index, s);
+        }
+        for (index, count) in other.s {
+            *self.s.entry(index).or_default() += count;
+        }
+        self.merge_functions(other.fn_map, other.f);
+        for (index, b) in other.branch_map {
+            self.branch_map.insert(index, b);
+        }
+        for (index, counts) in other.b {
+            let e = self.b.entry(index).or_default();
+            if e.len() < counts.len() {
+                e.resize(counts.len(), 0);
+            }
+            for (i, c) in counts.into_iter().enumerate() {
+                e[i] += c;
+            }
+        }
+    }
+
+    /// v8 每次报告的函数列表不一定相同，函数按源码位置合并，新出现的函数接着编号
+    fn merge_functions(&mut self, fn_map: HashMap<String, FnMap>, f: HashMap<String, u32>) {
+        let mut by_loc: HashMap<(u32, u32), String> = self
+            .fn_map
+            .iter()
+            .map(|(k, x)| ((x.loc.start.line, x.loc.start.column), k.clone()))
+            .collect();
+        let mut next = self
+            .fn_map
+            .keys()
+            .filter_map(|k| k.parse::<usize>().ok())
+            .max()
+            .map_or(0, |x| x + 1);
+        let mut fns: Vec<(String, FnMap)> = fn_map.into_iter().collect();
+        fns.sort_by_key(|(k, _)| k.parse::<usize>().unwrap_or(usize::MAX));
+        for (index, x) in fns {
+            let count = f.get(&index).copied().unwrap_or_default();
+            let key = by_loc
+                .entry((x.loc.start.line, x.loc.start.column))
+                .or_insert_with(|| {
+                    let key = next.to_string();
+                    next += 1;
+                    self.fn_map.insert(key.clone(), x);
+                    key
+                });
+            *self.f.entry(key.clone()).or_default() += count;
+        }
+    }
+}
+
+impl From<&CodeSpan> for Location {
+    fn from(s: &CodeSpan) -> Self {
+        Location {
+            start: Position {
+                line: s.start_line + 1,
+                column: s.start_column,
+            },
+            end: Position {
+                line: s.end_line + 1,
+                column: s.end_column,
+            },
+            skip: None,
+        }
+    }
 }
 
 // nyc 生成覆盖率报告需要源代码
 // 这里使用 source-map 生成源代码
 pub async fn generate_source_code(source_map: &SourceMap, output_dir: &str) -> Result<()> {
-    let tmp_dir = PathBuf::from(output_dir);
-    // 递归创建 tmp_dir 目录
-    fs::create_dir_all(&tmp_dir).await?;
-    for (i, content) in source_map.source_contents().enumerate() {
-        if let Some(p) = source_map.get_source(i as u32) {
-            if p.starts_with("external script ")
-                || p.starts_with("webpack")
-                || p.contains("node_modules")
-            {
+    SourceMaterializer::new(output_dir, &LinkOptions::default())
+        .add("", source_map)
+        .await
+        .map(|_| ())
+}
+
+/// 把多个 source map 里面的 sourcesContent 写到同一个目录
+///
+/// 同一个源码文件在不同 source map 里面内容不一致的时候，保留第一次写入的内容并告警。
+/// 哪些源码需要写入和映射的时候用同一个 `SourceFilter`，被过滤掉的第三方代码不会写出来
+pub struct SourceMaterializer {
+    output_dir: String,
+    options: LinkOptions,
+    // 已经写入的文件 -> (内容 hash, 来源的 source map)
+    written: HashMap<PathBuf, (String, String)>,
+    pub conflicts: usize,
+}
+
+impl SourceMaterializer {
+    pub fn new(output_dir: &str, options: &LinkOptions) -> Self {
+        SourceMaterializer {
+            output_dir: output_dir.to_string(),
+            options: options.clone(),
+            written: HashMap::new(),
+            conflicts: 0,
+        }
+    }
+
+    /// 返回新写入的文件数量
+    pub async fn add(&mut self, map_file: &str, source_map: &SourceMap) -> Result<usize> {
+        let tmp_dir = PathBuf::from(&self.output_dir);
+        // 递归创建 tmp_dir 目录
+        fs::create_dir_all(&tmp_dir).await?;
+        let filter = SourceFilter::new(source_map, &self.options);
+        let mut n = 0;
+        for (i, content) in source_map.source_contents().enumerate() {
+            let (Some(p), Some(content)) = (source_map.get_source(i as u32), content) else {
                 continue;
-            }
-            let path = tmp_dir.join(p);
-            if !path_normalize(&path.to_str().unwrap()).starts_with(output_dir) {
-                warn!("source 路径跳出了当前目录: {}", p);
+            };
+            if !filter.allow(i as u32, p) {
                 continue;
             }
+            let path = match safe_join(&tmp_dir, p) {
+                Ok(path) => path,
+                Err(e) => {
+                    warn!("跳过不安全的 source 路径 [{}] {}", map_file, e);
+                    continue;
+                }
+            };
+            let h = hash(content);
+            match self.written.get(&path) {
+                Some((prev, _)) if *prev == h => continue,
+                Some((_, prev_map)) => {
+                    self.conflicts += 1;
+                    warn!(
+                        "源码内容冲突，保留 {} 里面的版本，忽略 {}: {}",
+                        prev_map,
+                        map_file,
+                        path.to_string_lossy()
+                    );
+                    continue;
+                }
+                None => {}
+            }
             if let Some(parent) = path.parent() {
                 fs::create_dir_all(parent).await?;
             }
-            fs::write(&path, content.unwrap_or_default()).await?;
+            fs::write(&path, content).await?;
+            self.written.insert(path, (h, map_file.to_string()));
+            n += 1;
         }
+        Ok(n)
     }
-
-    Ok(())
 }
 
-#[instrument]
-pub fn from(vs: &Vec<MappingItem>, base_dir: &str) -> HashMap<String, IstanbulCov> {
+/// 源码位置 (line, column) -> (函数, 计数)
+type FunctionsByLoc = BTreeMap<(u32, u32), (FnMap, u32)>;
+
+#[instrument(skip_all)]
+pub fn from(
+    vs: &[MappingItem],
+    fns: &[FunctionItem],
+    base_dir: &str,
+) -> HashMap<String, IstanbulCov> {
     let base = Path::new(base_dir);
-    let mut m = HashMap::new();
+    let mut m: HashMap<String, IstanbulCov> = HashMap::new();
     for (key, x) in vs.iter().enumerate() {
         let abs_path = path_normalize(base.join(&x.source).to_str().unwrap_or_default());
-        let mut ic = IstanbulCov::default();
-        ic.path = abs_path.to_string();
-        let entry = m.entry(ic.path.clone()).or_insert(ic);
+        let entry = m.entry(abs_path.clone()).or_insert(IstanbulCov {
+            path: abs_path,
+            ..Default::default()
+        });
         entry.statement_map.insert(
             key.to_string(),
             StatementMap {
@@ -103,13 +264,99 @@ pub fn from(vs: &Vec<MappingItem>, base_dir: &str) -> HashMap<String, IstanbulCo
                     line: x.last_original_line + 1,
                     column: x.last_original_column,
                 },
+                skip: x.skip.then_some(true),
             },
         );
         entry.s.insert(key.to_string(), x.count);
     }
+    // 同一个源码函数可能对应多个 v8 函数，先按源码位置去重，再按位置顺序编号
+    let mut by_file: HashMap<String, FunctionsByLoc> = HashMap::new();
+    for x in fns {
+        let abs_path = path_normalize(base.join(&x.source).to_str().unwrap_or_default());
+        let e = by_file
+            .entry(abs_path)
+            .or_default()
+            .entry((x.loc.start_line, x.loc.start_column))
+            .or_insert_with(|| {
+                (
+                    FnMap {
+                        name: x.name.clone(),
+                        line: x.loc.start_line as i32 + 1,
+                        loc: Location::from(&x.loc),
+                        decl: Some(Location::from(&x.decl)),
+                        skip: x.skip.then_some(true),
+                    },
+                    0,
+                )
+            });
+        e.1 += x.count;
+    }
+    for (abs_path, fns) in by_file {
+        let entry = m.entry(abs_path.clone()).or_insert(IstanbulCov {
+            path: abs_path,
+            ..Default::default()
+        });
+        for (key, (f, count)) in fns.into_values().enumerate() {
+            entry.fn_map.insert(key.to_string(), f);
+            entry.f.insert(key.to_string(), count);
+        }
+    }
     m
 }
 
+fn span_range(s: &CodeSpan) -> CoverageRange {
+    CoverageRange {
+        start_offset: s.start,
+        end_offset: s.end,
+        count: 0,
+    }
+}
+
+/// 没有 source map 的脚本，直接用生成代码统计覆盖率
+#[instrument(skip(parsed, cov_tree))]
+pub fn from_generated(
+    parsed: &ParsedCode,
+    cov_tree: &CoverRangeNodeRead,
+    path: &str,
+) -> IstanbulCov {
+    let mut ic = IstanbulCov {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    for (key, x) in parsed.statements.iter().enumerate() {
+        let loc = Location::from(x);
+        ic.statement_map.insert(
+            key.to_string(),
+            StatementMap {
+                start: loc.start,
+                end: loc.end,
+                skip: None,
+            },
+        );
+        ic.s.insert(
+            key.to_string(),
+            find_root_value_only(cov_tree, &span_range(x)).unwrap_or_default(),
+        );
+    }
+    for (key, x) in parsed.functions.iter().enumerate() {
+        ic.fn_map.insert(
+            key.to_string(),
+            FnMap {
+                name: x.name.clone(),
+                line: x.loc.start_line as i32 + 1,
+                loc: Location::from(&x.loc),
+                decl: Some(Location::from(&x.decl)),
+                skip: None,
+            },
+        );
+        ic.f.insert(
+            key.to_string(),
+            find_root_value_only(cov_tree, &span_range(&x.loc)).unwrap_or_default(),
+        );
+    }
+    ic
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -122,6 +369,184 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_materialize_conflict() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("v8-to-istanbul-sources-{}", std::process::id()));
+        let dir = dir.to_string_lossy().to_string();
+        let map = |content: &str| {
+            SourceMap::from_slice(
+                serde_json::json!({
+                    "version": 3,
+                    "sources": ["src/a.js", "../escape.js"],
+                    "sourcesContent": [content, "x"],
+                    "names": [],
+                    "mappings": ""
+                })
+                .to_string()
+                .as_bytes(),
+            )
+        };
+        let mut m = SourceMaterializer::new(&dir, &LinkOptions::default());
+        assert_eq!(m.add("a.map", &map("let a = 1")?).await?, 1);
+        assert_eq!(m.add("b.map", &map("let a = 1")?).await?, 0);
+        assert_eq!(m.conflicts, 0);
+        assert_eq!(m.add("c.map", &map("let a = 2")?).await?, 0);
+        assert_eq!(m.conflicts, 1);
+        assert_eq!(
+            fs::read_to_string(PathBuf::from(&dir).join("src/a.js")).await?,
+            "let a = 1"
+        );
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_materialize_malicious() -> Result<()> {
+        let root = std::env::temp_dir().join(format!("v8-to-istanbul-evil-{}", std::process::id()));
+        let dir = root.join("out");
+        let sm = SourceMap::from_slice(
+            serde_json::json!({
+                "version": 3,
+                "sources": [
+                    "../out-evil/a.js",
+                    "/tmp/abs.js",
+                    "C:\\Windows\\a.js",
+                    "a\0.js",
+                    "src/../../../b.js",
+                    "src/./ok.js"
+                ],
+                "sourcesContent": ["1", "2", "3", "4", "5", "6"],
+                "names": [],
+                "mappings": ""
+            })
+            .to_string()
+            .as_bytes(),
+        )?;
+        let mut m = SourceMaterializer::new(&dir.to_string_lossy(), &LinkOptions::default());
+        assert_eq!(m.add("evil.map", &sm).await?, 1);
+        assert_eq!(fs::read_to_string(dir.join("src/ok.js")).await?, "6");
+        assert!(!fs::try_exists(root.join("out-evil")).await?);
+        fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_materialize_ignore_list() -> Result<()> {
+        let root =
+            std::env::temp_dir().join(format!("v8-to-istanbul-ignore-{}", std::process::id()));
+        let sm = SourceMap::from_slice(
+            serde_json::json!({
+                "version": 3,
+                "sources": ["src/a.js", "src/vendor.js", "node_modules/x/index.js"],
+                "sourcesContent": ["1", "2", "3"],
+                "ignoreList": [1],
+                "names": [],
+                "mappings": ""
+            })
+            .to_string()
+            .as_bytes(),
+        )?;
+        // 和映射的时候一样按 ignoreList 过滤
+        let dir = root.join("respect");
+        let mut m = SourceMaterializer::new(&dir.to_string_lossy(), &LinkOptions::default());
+        assert_eq!(m.add("a.map", &sm).await?, 2);
+        assert!(!fs::try_exists(dir.join("src/vendor.js")).await?);
+        assert!(fs::try_exists(dir.join("node_modules/x/index.js")).await?);
+
+        // --include-ignored 的时候 ignoreList 里面的源码也会被映射，需要写出来
+        let dir = root.join("include");
+        let options = LinkOptions {
+            respect_ignore_list: false,
+        };
+        let mut m = SourceMaterializer::new(&dir.to_string_lossy(), &options);
+        assert_eq!(m.add("a.map", &sm).await?, 2);
+        assert_eq!(fs::read_to_string(dir.join("src/vendor.js")).await?, "2");
+        assert!(!fs::try_exists(dir.join("node_modules/x/index.js")).await?);
+
+        fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+    #[test]
+    fn test_from_generated() -> Result<()> {
+        use crate::format::script_coverage::{
+            build_coverage_range_tree, read_only, CoverRangeNode, ScriptCoverage,
+        };
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let sc: Vec<ScriptCoverage> =
+            serde_json::from_str(include_str!("../../tests/base/v8-coverage.json"))?;
+        let root = Rc::new(RefCell::new(CoverRangeNode::new(&CoverageRange {
+            start_offset: 0,
+            end_offset: sc[0].source.len() as u32,
+            count: 0,
+        })));
+        build_coverage_range_tree(root.clone(), &sc[0].functions);
+        let cov_tree = read_only(root);
+        let parsed = crate::traverse::parse_code(&sc[0].source, "main.min.js")?;
+        let ic = from_generated(&parsed, &cov_tree, "/out/main.min.js");
+
+        assert_eq!(ic.path, "/out/main.min.js");
+        assert_eq!(ic.statement_map.len(), parsed.statements.len());
+        assert_eq!(ic.fn_map.len(), parsed.functions.len());
+        assert!(ic.f.values().any(|&c| c > 0));
+        assert!(ic.s.values().any(|&c| c == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_count_mode() {
+        let cov = |count_mode| IstanbulCov {
+            path: "/a.js".to_string(),
+            s: HashMap::from([("0".to_string(), 1)]),
+            count_mode,
+            ..Default::default()
+        };
+        let mut ic = IstanbulCov::default();
+        ic.merge(cov(Some(CountMode::Block)));
+        assert_eq!(ic.count_mode, Some(CountMode::Block));
+        ic.merge(cov(None));
+        assert_eq!(ic.count_mode, Some(CountMode::Block));
+        ic.merge(cov(Some(CountMode::Binary)));
+        assert_eq!(ic.count_mode, Some(CountMode::Binary));
+        assert_eq!(ic.s["0"], 3);
+        let json = serde_json::to_value(&ic).unwrap();
+        assert_eq!(json["countMode"], "binary");
+    }
+
+    #[test]
+    fn test_function_keys() {
+        let span = |line, column| CodeSpan {
+            start_line: line,
+            start_column: column,
+            end_line: line + 1,
+            ..Default::default()
+        };
+        let item = |name: &str, line, count| FunctionItem {
+            source: "a.js".to_string(),
+            name: name.to_string(),
+            decl: span(line, 9),
+            loc: span(line, 0),
+            count,
+            skip: false,
+        };
+        let report = |fns: &[FunctionItem]| from(&[], fns, "/p").remove("/p/a.js").unwrap();
+
+        // 同一个位置的函数只保留一个，计数累加，key 按位置顺序编号
+        let mut ic = report(&[item("g", 5, 1), item("f", 1, 2), item("g", 5, 3)]);
+        assert_eq!(ic.fn_map["0"].name, "f");
+        assert_eq!(ic.fn_map["1"].name, "g");
+        assert_eq!((ic.f["0"], ic.f["1"]), (2, 4));
+
+        // 合并的时候按位置对应，另一份报告里面的编号不一样也没关系
+        ic.merge(report(&[item("h", 3, 1), item("g", 5, 1)]));
+        assert_eq!(ic.fn_map.len(), 3);
+        assert_eq!(ic.fn_map["2"].name, "h");
+        assert_eq!((ic.f["0"], ic.f["1"], ic.f["2"]), (2, 5, 1));
+    }
+
     #[test]
     fn test_join() {
         let a1 = PathBuf::from("/abc/def");
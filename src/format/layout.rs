@@ -0,0 +1,278 @@
+use crate::format::istanbul::IstanbulCov;
+use crate::format::path_normalize;
+use crate::fputil::hash;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tracing::info;
+
+/// 输出文件的目录结构
+///
+/// - `report_dir`：sonar / clover 等报告
+/// - `temp_dir`：和 nyc 的 `.nyc_output` 一样，存放每个测试的覆盖率和 `processinfo`
+/// - `merged_file`：合并之后的 istanbul 覆盖率，比如 `.nyc_output/merged.json` 或者 `coverage-final.json`
+#[derive(Debug, Clone)]
+pub struct OutputLayout {
+    pub output_dir: PathBuf,
+    pub report_dir: PathBuf,
+    pub temp_dir: PathBuf,
+    pub merged_file: PathBuf,
+    pub pretty: bool,
+}
+
+/// nyc `processinfo` 目录里面每个进程的描述，字段和 nyc 保持一致
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessInfo {
+    pub parent: Option<String>,
+    pub pid: u32,
+    pub ppid: u32,
+    pub argv: Vec<String>,
+    pub exec_argv: Vec<String>,
+    pub cwd: String,
+    pub time: u128,
+    pub coverage_filename: String,
+    pub external_id: String,
+    pub uuid: String,
+    pub files: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProcessTreeNode {
+    parent: Option<String>,
+    children: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProcessIndex {
+    processes: BTreeMap<String, ProcessTreeNode>,
+    files: BTreeMap<String, Vec<String>>,
+    external_ids: BTreeMap<String, String>,
+}
+
+/// 测试名称生成固定的 uuid，多次运行输出的文件名不变
+pub fn test_uuid(test_name: &str) -> String {
+    let h = hash(test_name);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &h[0..8],
+        &h[8..12],
+        &h[12..16],
+        &h[16..20],
+        &h[20..32]
+    )
+}
+
+fn join(base: &Path, p: &str) -> PathBuf {
+    PathBuf::from(path_normalize(&base.join(p).to_string_lossy()))
+}
+
+impl OutputLayout {
+    /// `report_dir`、`temp_dir`、`merged_file` 是相对路径的时候，相对 `output_dir`
+    pub fn new(
+        output_dir: &Path,
+        report_dir: &str,
+        temp_dir: &str,
+        merged_file: &str,
+        pretty: bool,
+    ) -> Self {
+        OutputLayout {
+            output_dir: output_dir.to_path_buf(),
+            report_dir: join(output_dir, report_dir),
+            temp_dir: join(output_dir, temp_dir),
+            merged_file: join(output_dir, merged_file),
+            pretty,
+        }
+    }
+
+    /// 合并结果所在的目录，json-summary 报告也输出在这里
+    pub fn merged_dir(&self) -> PathBuf {
+        self.merged_file
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or(self.output_dir.clone())
+    }
+
+    pub fn processinfo_dir(&self) -> PathBuf {
+        self.temp_dir.join("processinfo")
+    }
+
+    pub async fn prepare(&self) -> Result<()> {
+        for d in [&self.report_dir, &self.temp_dir, &self.merged_dir()] {
+            fs::create_dir_all(d)
+                .await
+                .map_err(|e| anyhow!("创建目录失败 [{}] {}", d.to_string_lossy(), e))?;
+        }
+        Ok(())
+    }
+
+    pub fn to_json<T: Serialize>(&self, v: &T) -> Result<Vec<u8>> {
+        Ok(if self.pretty {
+            serde_json::to_vec_pretty(v)?
+        } else {
+            serde_json::to_vec(v)?
+        })
+    }
+
+    pub async fn write<C: AsRef<[u8]>>(&self, d: &Path, b: C) -> Result<()> {
+        fs::write(d, b)
+            .await
+            .map_err(|e| anyhow!("写入报告失败 [{}] {}", d.to_string_lossy(), e))?;
+        info!("输出报告 {}", d.to_string_lossy());
+        Ok(())
+    }
+
+    pub async fn write_merged(&self, merged: &HashMap<String, IstanbulCov>) -> Result<()> {
+        self.write(&self.merged_file, self.to_json(merged)?).await
+    }
+
+    /// 按 nyc 的格式输出单个测试的覆盖率，`{temp_dir}/{uuid}.json`
+    pub async fn write_process(
+        &self,
+        test_name: &str,
+        cov: &HashMap<String, IstanbulCov>,
+    ) -> Result<ProcessInfo> {
+        let uuid = test_uuid(test_name);
+        let coverage_file = self.temp_dir.join(format!("{}.json", uuid));
+        self.write(&coverage_file, self.to_json(cov)?).await?;
+
+        let mut files: Vec<String> = cov.keys().cloned().collect();
+        files.sort();
+        let info = ProcessInfo {
+            parent: None,
+            pid: std::process::id(),
+            ppid: 0,
+            argv: std::env::args().collect(),
+            exec_argv: vec![],
+            cwd: std::env::current_dir()?.to_string_lossy().to_string(),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or_default(),
+            coverage_filename: coverage_file.to_string_lossy().to_string(),
+            external_id: test_name.to_string(),
+            uuid: uuid.clone(),
+            files,
+        };
+        fs::create_dir_all(self.processinfo_dir()).await?;
+        self.write(
+            &self.processinfo_dir().join(format!("{}.json", uuid)),
+            self.to_json(&info)?,
+        )
+        .await?;
+        Ok(info)
+    }
+
+    /// `processinfo/index.json`，nyc 根据它找到每个文件被哪些进程覆盖
+    pub async fn write_process_index(
+        &self,
+        infos: impl IntoIterator<Item = &ProcessInfo>,
+    ) -> Result<()> {
+        let mut index = ProcessIndex {
+            processes: BTreeMap::new(),
+            files: BTreeMap::new(),
+            external_ids: BTreeMap::new(),
+        };
+        for info in infos {
+            index.processes.insert(
+                info.uuid.clone(),
+                ProcessTreeNode {
+                    parent: None,
+                    children: vec![],
+                },
+            );
+            for f in info.files.iter() {
+                index
+                    .files
+                    .entry(f.clone())
+                    .or_default()
+                    .push(info.uuid.clone());
+            }
+            index
+                .external_ids
+                .insert(info.external_id.clone(), info.uuid.clone());
+        }
+        fs::create_dir_all(self.processinfo_dir()).await?;
+        self.write(
+            &self.processinfo_dir().join("index.json"),
+            self.to_json(&index)?,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let l = OutputLayout::new(
+            Path::new("/out"),
+            "report",
+            ".nyc_output",
+            "coverage-final.json",
+            false,
+        );
+        assert_eq!(l.report_dir, PathBuf::from("/out/report"));
+        assert_eq!(
+            OutputLayout::new(Path::new("/out"), ".", ".", "a.json", true).report_dir,
+            PathBuf::from("/out")
+        );
+        assert_eq!(l.merged_dir(), PathBuf::from("/out"));
+        assert_eq!(
+            l.processinfo_dir(),
+            PathBuf::from("/out/.nyc_output/processinfo")
+        );
+        let l = OutputLayout::new(
+            Path::new("/out"),
+            ".",
+            "/tmp/nyc",
+            ".nyc_output/merged.json",
+            true,
+        );
+        assert_eq!(l.temp_dir, PathBuf::from("/tmp/nyc"));
+        assert_eq!(l.merged_dir(), PathBuf::from("/out/.nyc_output"));
+    }
+
+    #[test]
+    fn test_process_uuid() {
+        let u = test_uuid("a.test.js");
+        assert_eq!(u.len(), 36);
+        assert_eq!(u, test_uuid("a.test.js"));
+        assert_ne!(u, test_uuid("b.test.js"));
+    }
+
+    #[tokio::test]
+    async fn test_write_process() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("v8-to-istanbul-layout-{}", std::process::id()));
+        let l = OutputLayout::new(&dir, ".", ".nyc_output", "coverage-final.json", false);
+        l.prepare().await?;
+        let cov = HashMap::from([(
+            "/p/a.js".to_string(),
+            IstanbulCov {
+                path: "/p/a.js".to_string(),
+                ..Default::default()
+            },
+        )]);
+        let info = l.write_process("t1", &cov).await?;
+        l.write_process_index(&[info]).await?;
+        l.write_merged(&cov).await?;
+
+        let uuid = test_uuid("t1");
+        let index: serde_json::Value =
+            serde_json::from_slice(&fs::read(l.processinfo_dir().join("index.json")).await?)?;
+        assert_eq!(index["files"]["/p/a.js"][0], uuid.as_str());
+        assert_eq!(index["externalIds"]["t1"], uuid.as_str());
+        assert!(fs::try_exists(l.temp_dir.join(format!("{}.json", uuid))).await?);
+        assert!(fs::try_exists(dir.join("coverage-final.json")).await?);
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
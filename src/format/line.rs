@@ -0,0 +1,134 @@
+use crate::format::istanbul::IstanbulCov;
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+
+/// 同一行有多个语句、而且执行次数不同的时候，行覆盖率的取值方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum LineMode {
+    /// 取最小值，行内所有语句都执行过才算覆盖
+    Min,
+    /// 取最大值，行内任意语句执行过就算覆盖，和 istanbul 的 `getLineCoverage` 一致
+    #[default]
+    Max,
+}
+
+/// 根据语句覆盖率计算行覆盖率，key 是从 1 开始的行号
+///
+/// 和 istanbul 的 `getLineCoverage` 一样，语句只计入它的起始行，被 ignore 注释排除的语句也计入
+pub fn line_coverage(ic: &IstanbulCov, mode: LineMode) -> BTreeMap<u32, u32> {
+    let mut lines: BTreeMap<u32, u32> = BTreeMap::new();
+    for (key, st) in ic.statement_map.iter() {
+        let count = ic.s.get(key).copied().unwrap_or_default();
+        lines
+            .entry(st.start.line)
+            .and_modify(|c| {
+                *c = match mode {
+                    LineMode::Min => (*c).min(count),
+                    LineMode::Max => (*c).max(count),
+                }
+            })
+            .or_insert(count);
+    }
+    lines
+}
+
+/// 生成 istanbul 旧格式里面的 `l` 字段
+pub fn fill_legacy_lines(ic: &mut IstanbulCov, mode: LineMode) {
+    ic.l = Some(
+        line_coverage(ic, mode)
+            .into_iter()
+            .map(|(line, count)| (line.to_string(), count))
+            .collect(),
+    );
+}
+
+/// 每一行的分支数和已覆盖的分支数，key 是从 1 开始的行号，被排除的分支算作已覆盖
+pub fn branch_coverage(ic: &IstanbulCov) -> BTreeMap<u32, (u32, u32)> {
+    let mut lines: BTreeMap<u32, (u32, u32)> = BTreeMap::new();
+    for (key, br) in ic.branch_map.iter() {
+        let counts = ic.b.get(key).cloned().unwrap_or_default();
+        let e = lines.entry(br.line.max(1) as u32).or_default();
+        e.0 += counts.len() as u32;
+        e.1 += counts
+            .iter()
+            .enumerate()
+            .filter(|&(i, &c)| c > 0 || br.locations.get(i).and_then(|l| l.skip) == Some(true))
+            .count() as u32;
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::istanbul::{BranchMap, Position, StatementMap};
+
+    fn statement(line: u32, column: u32) -> StatementMap {
+        StatementMap {
+            start: Position { line, column },
+            end: Position {
+                line,
+                column: column + 5,
+            },
+            skip: None,
+        }
+    }
+
+    #[test]
+    fn test_line_coverage() {
+        let mut ic = IstanbulCov::default();
+        for (key, line, column, count) in [("0", 1, 0, 3), ("1", 1, 10, 0), ("2", 2, 0, 1)] {
+            ic.statement_map
+                .insert(key.to_string(), statement(line, column));
+            ic.s.insert(key.to_string(), count);
+        }
+        let max = line_coverage(&ic, LineMode::Max);
+        assert_eq!(max, BTreeMap::from([(1, 3), (2, 1)]));
+        let min = line_coverage(&ic, LineMode::Min);
+        assert_eq!(min, BTreeMap::from([(1, 0), (2, 1)]));
+
+        fill_legacy_lines(&mut ic, LineMode::Max);
+        let json = serde_json::to_value(&ic).unwrap();
+        assert_eq!(json["l"]["1"], 3);
+        assert_eq!(json["l"]["2"], 1);
+    }
+
+    #[test]
+    fn test_line_coverage_skipped() {
+        let mut ic = IstanbulCov::default();
+        ic.statement_map.insert(
+            "0".to_string(),
+            StatementMap {
+                skip: Some(true),
+                ..statement(4, 0)
+            },
+        );
+        ic.s.insert("0".to_string(), 0);
+        // istanbul 不检查 skip，只有被排除的语句的行也会出现
+        assert_eq!(line_coverage(&ic, LineMode::Max), BTreeMap::from([(4, 0)]));
+    }
+
+    #[test]
+    fn test_branch_coverage() {
+        let mut ic = IstanbulCov::default();
+        for (key, line, counts) in [
+            ("0", 3, vec![1, 0]),
+            ("1", 3, vec![0, 0]),
+            ("2", 5, vec![2]),
+        ] {
+            ic.branch_map.insert(
+                key.to_string(),
+                BranchMap {
+                    line,
+                    r#type: "if".to_string(),
+                    locations: vec![],
+                },
+            );
+            ic.b.insert(key.to_string(), counts);
+        }
+        assert_eq!(
+            branch_coverage(&ic),
+            BTreeMap::from([(3, (4, 1)), (5, (1, 1))])
+        );
+    }
+}
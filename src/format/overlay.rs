@@ -0,0 +1,296 @@
+use crate::format::script_coverage::{mapping_count, CoverRangeNodeRead};
+use crate::format::{xml_escape, MappingItem};
+use crate::translate::line_offsets;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// 一段互不重叠的区间 `[start, end)`，count 是最内层 v8 区间的计数，不在任何区间内的时候是 None
+type Segment = (u32, u32, Option<u32>);
+
+/// 把覆盖率搜索树展开成互不重叠的区间，根节点是覆盖整个脚本的虚拟区间，不算计数
+pub fn count_segments(tree: &CoverRangeNodeRead) -> Vec<Segment> {
+    fn flatten(node: &CoverRangeNodeRead, value: Option<u32>, out: &mut Vec<Segment>) {
+        let mut pos = node.left;
+        for c in node.children.iter() {
+            if c.left > pos {
+                out.push((pos, c.left, value));
+            }
+            flatten(c, Some(c.value), out);
+            pos = pos.max(c.right);
+        }
+        if pos < node.right {
+            out.push((pos, node.right, value));
+        }
+    }
+    let mut out = vec![];
+    flatten(tree, None, &mut out);
+    out
+}
+
+fn count_at(segments: &[Segment], offset: u32) -> Option<u32> {
+    let i = segments.partition_point(|s| s.0 <= offset).checked_sub(1)?;
+    let (_, end, count) = segments[i];
+    if offset < end {
+        count
+    } else {
+        None
+    }
+}
+
+/// 在所有边界处切开文本，每一段带上覆盖它的 span 编号
+///
+/// spans 是 `[start, end)`，可以互相重叠
+fn pieces(
+    len: u32,
+    bounds: &BTreeSet<u32>,
+    spans: &[(u32, u32, usize)],
+) -> Vec<(u32, u32, Vec<usize>)> {
+    let mut bounds: Vec<u32> = bounds.iter().copied().filter(|&b| b < len).collect();
+    bounds.extend(spans.iter().flat_map(|s| [s.0, s.1]).filter(|&b| b < len));
+    bounds.push(0);
+    bounds.push(len);
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let mut spans = spans.to_vec();
+    spans.sort_unstable_by_key(|s| s.0);
+    let mut next = 0;
+    let mut active: Vec<(u32, usize)> = vec![];
+    let mut r = vec![];
+    for w in bounds.windows(2) {
+        let (start, end) = (w[0], w[1]);
+        while next < spans.len() && spans[next].0 <= start {
+            active.push((spans[next].1, spans[next].2));
+            next += 1;
+        }
+        active.retain(|a| a.0 > start);
+        let mut ids: Vec<usize> = active.iter().map(|a| a.1).collect();
+        ids.sort_unstable();
+        r.push((start, end, ids));
+    }
+    r
+}
+
+fn count_class(count: Option<u32>) -> &'static str {
+    match count {
+        None => "",
+        Some(0) => "c0",
+        Some(1) => "c1",
+        Some(2..=9) => "c2",
+        _ => "c3",
+    }
+}
+
+fn render_pieces(
+    out: &mut String,
+    text: &[char],
+    pieces: &[(u32, u32, Vec<usize>)],
+    count: impl Fn(u32, &[usize]) -> Option<u32>,
+) {
+    for (start, end, ids) in pieces {
+        let s: String = text[*start as usize..*end as usize].iter().collect();
+        let count = count(*start, ids);
+        if ids.is_empty() && count.is_none() {
+            out.push_str(&xml_escape(&s));
+            continue;
+        }
+        let mut class = count_class(count).to_string();
+        out.push_str("<span");
+        if !ids.is_empty() {
+            class = format!("{} m", class).trim_start().to_string();
+            let ids: Vec<String> = ids.iter().map(|i| i.to_string()).collect();
+            out.push_str(&format!(" data-m=\"{}\"", ids.join(" ")));
+        }
+        out.push_str(&format!(" class=\"{}\"", class));
+        if let Some(count) = count {
+            out.push_str(&format!(" title=\"count={}\"", count));
+        }
+        out.push_str(&format!(">{}</span>", xml_escape(&s)));
+    }
+}
+
+const STYLE: &str = r#"body{margin:0;font-family:sans-serif}
+.panes{display:flex;height:100vh}
+.pane{flex:1;overflow:auto;padding:0 8px;border-right:1px solid #ccc}
+pre{white-space:pre-wrap;word-break:break-all;font-size:12px}
+.c0{background:#f6c6ce}.c1{background:#e6f5d0}.c2{background:#c5e8a0}.c3{background:#9fd36b}
+.m{cursor:pointer}.hl{outline:2px solid #2060d0;background:#cfe0ff}"#;
+
+/// 鼠标悬停的时候高亮两边 data-m 相同的片段，点击的时候滚动到另一边
+const SCRIPT: &str = r#"<script>(()=>{const hl=t=>{document.querySelectorAll(".hl").forEach(x=>x.classList.remove("hl"));const r=[];if(t)for(const id of t.dataset.m.split(" "))document.querySelectorAll(`[data-m~="${id}"]`).forEach(x=>{x.classList.add("hl");r.push(x)});return r};document.addEventListener("mouseover",e=>hl(e.target.closest("[data-m]")));document.addEventListener("click",e=>{const t=e.target.closest("[data-m]");if(!t)return;const pane=t.closest(".pane");const o=hl(t).find(x=>x.closest(".pane")!==pane);if(o)o.scrollIntoView({block:"center"})})})()</script>"#;
+
+/// 生成代码和源码并排显示的 html
+///
+/// 左边是生成代码，按 v8 区间的计数着色；右边是 mapping 涉及到的源码，
+/// 按映射过去的计数着色。两边的片段用 mapping 的下标关联
+pub fn render_overlay(
+    script: &str,
+    code: &str,
+    mapping: &[MappingItem],
+    tree: &CoverRangeNodeRead,
+    sources: &HashMap<String, String>,
+) -> String {
+    let segments = count_segments(tree);
+    let code: Vec<char> = code.chars().collect();
+    let len = code.len() as u32;
+
+    let generated_spans: Vec<(u32, u32, usize)> = mapping
+        .iter()
+        .enumerate()
+        .map(|(i, m)| (m.generated_column, m.last_generated_column + 1, i))
+        .collect();
+    // 和 convert 一样取包含整个 MappingItem 的最内层区间，两边的计数才能对得上
+    let item_count: Vec<Option<u32>> = mapping.iter().map(|m| mapping_count(tree, m)).collect();
+    let bounds: BTreeSet<u32> = segments.iter().flat_map(|s| [s.0, s.1]).collect();
+
+    let mut out = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{0}</title><style>{1}</style></head><body><div class=\"panes\"><div class=\"pane\"><h3>{0}</h3><pre>",
+        xml_escape(script),
+        STYLE
+    );
+    render_pieces(
+        &mut out,
+        &code,
+        &pieces(len, &bounds, &generated_spans),
+        |start, _| count_at(&segments, start),
+    );
+    out.push_str("</pre></div><div class=\"pane\">");
+
+    let mut by_source: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (i, m) in mapping.iter().enumerate() {
+        by_source.entry(&m.source).or_default().push(i);
+    }
+    for (source, ids) in by_source {
+        out.push_str(&format!("<h3>{}</h3>", xml_escape(source)));
+        let Some(content) = sources.get(source) else {
+            out.push_str("<p>source map 里面没有这个文件的源码</p>");
+            continue;
+        };
+        let text: Vec<char> = content.chars().collect();
+        let sect = line_offsets(content);
+        let len = text.len() as u32;
+        let offset = |line: u32, column: u32| -> u32 {
+            sect.get(line as usize)
+                .map(|s| s + column)
+                .unwrap_or(len)
+                .min(len)
+        };
+        let spans: Vec<(u32, u32, usize)> = ids
+            .iter()
+            .map(|&i| {
+                let m = &mapping[i];
+                let start = offset(m.original_line, m.original_column);
+                let end = offset(m.last_original_line, m.last_original_column) + 1;
+                (start, end.max(start + 1), i)
+            })
+            .collect();
+        out.push_str("<pre>");
+        render_pieces(
+            &mut out,
+            &text,
+            &pieces(len, &BTreeSet::new(), &spans),
+            |_, ids| ids.iter().filter_map(|&i| item_count[i]).max(),
+        );
+        out.push_str("</pre>");
+    }
+    out.push_str("</div></div>");
+    out.push_str(SCRIPT);
+    out.push_str("</body></html>");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::script_coverage::{
+        coverage_tree, CoverageRange, FunctionCoverage, ScriptCoverage,
+    };
+
+    fn item(gs: u32, ge: u32, line: u32, column: u32, last_column: u32) -> MappingItem {
+        MappingItem {
+            source: "a.js".to_string(),
+            generated_column: gs,
+            last_generated_column: ge,
+            original_line: line,
+            original_column: column,
+            last_original_line: line,
+            last_original_column: last_column,
+            count: 0,
+            idx: 0,
+            skip: false,
+        }
+    }
+
+    fn tree(source: &str) -> CoverRangeNodeRead {
+        let range = |start_offset, end_offset, count| CoverageRange {
+            start_offset,
+            end_offset,
+            count,
+        };
+        coverage_tree(&ScriptCoverage {
+            url: "a.min.js".to_string(),
+            source: source.to_string(),
+            functions: vec![FunctionCoverage {
+                function_name: "".to_string(),
+                ranges: vec![range(0, 10, 1), range(4, 8, 0)],
+                is_block_coverage: true,
+            }],
+            script_url: "".to_string(),
+            binary: false,
+        })
+    }
+
+    #[test]
+    fn test_count_segments() {
+        let segments = count_segments(&tree("a();b();c();"));
+        assert_eq!(
+            segments,
+            vec![
+                (0, 4, Some(1)),
+                (4, 8, Some(0)),
+                (8, 10, Some(1)),
+                (10, 12, None)
+            ]
+        );
+        assert_eq!(count_at(&segments, 5), Some(0));
+        assert_eq!(count_at(&segments, 11), None);
+    }
+
+    #[test]
+    fn test_pieces() {
+        let r = pieces(6, &BTreeSet::from([3]), &[(0, 2, 0), (1, 4, 1)]);
+        assert_eq!(
+            r,
+            vec![
+                (0, 1, vec![0]),
+                (1, 2, vec![0, 1]),
+                (2, 3, vec![1]),
+                (3, 4, vec![1]),
+                (4, 6, vec![])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_overlay() {
+        let code = "a();b();c();";
+        let mapping = vec![item(0, 3, 0, 0, 3), item(4, 7, 1, 0, 3)];
+        let sources = HashMap::from([("a.js".to_string(), "a();\nb<>;\n".to_string())]);
+        let html = render_overlay("a.min.js", code, &mapping, &tree(code), &sources);
+        assert!(html.contains(r#"<span data-m="0" class="c1 m" title="count=1">a();</span>"#));
+        assert!(html.contains(r#"<span data-m="1" class="c0 m" title="count=0">b();</span>"#));
+        // 源码按映射过去的计数着色
+        assert!(html.contains(r#"<span data-m="1" class="c0 m" title="count=0">b&lt;&gt;;</span>"#));
+        // 没有 mapping 的代码只按计数着色，不在任何区间内的代码不加标签
+        assert!(html.contains(r#"<span class="c1" title="count=1">c(</span>);</pre>"#));
+    }
+
+    #[test]
+    fn test_render_overlay_crossing() {
+        // MappingItem 跨过了嵌套区间的结尾，计数取包含整个 MappingItem 的区间，和 convert 一致
+        let code = "a();b();c();";
+        let mapping = vec![item(5, 9, 0, 0, 3)];
+        let sources = HashMap::from([("a.js".to_string(), "a();\n".to_string())]);
+        let html = render_overlay("a.min.js", code, &mapping, &tree(code), &sources);
+        assert!(html.contains(r#"<span data-m="0" class="c1 m" title="count=1">a();</span>"#));
+    }
+}
@@ -0,0 +1,122 @@
+use crate::format::clover::render_clover;
+use crate::format::codecov::render_codecov;
+use crate::format::coveralls::render_coveralls;
+use crate::format::istanbul::IstanbulCov;
+use crate::format::layout::OutputLayout;
+use crate::format::line::LineMode;
+use crate::format::sonar::render_sonar;
+use crate::format::summary::JsonSummary;
+use crate::format::text::{render_text, render_text_summary, terminal_width};
+use anyhow::Result;
+use clap::ValueEnum;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tracing::{instrument, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Reporter {
+    /// 控制台输出每个文件的覆盖率表格
+    Text,
+    /// 控制台输出覆盖率汇总
+    TextSummary,
+    /// 和合并结果同目录输出 istanbul 格式的 coverage-summary.json
+    JsonSummary,
+    /// 输出 SonarQube generic coverage 格式的 sonar-coverage.xml
+    Sonar,
+    /// 输出 Clover 格式的 clover.xml
+    Clover,
+    /// 输出 Coveralls 的 source_files 数据 coveralls.json
+    Coveralls,
+    /// 输出 Codecov 的 JSON 覆盖率格式 codecov.json
+    Codecov,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportOptions {
+    pub layout: OutputLayout,
+    pub line_mode: LineMode,
+    /// text reporter 不显示 100% 覆盖的文件
+    pub skip_full: bool,
+    /// sonar 等报告里面的文件路径都相对这个目录
+    pub project_root: PathBuf,
+}
+
+#[instrument(skip(merged))]
+pub async fn write_reports(
+    reporters: &[Reporter],
+    merged: &HashMap<String, IstanbulCov>,
+    options: &ReportOptions,
+) -> Result<()> {
+    let sorted: BTreeMap<&str, &IstanbulCov> =
+        merged.iter().map(|(k, v)| (k.as_str(), v)).collect();
+    let layout = &options.layout;
+    for reporter in reporters {
+        match reporter {
+            Reporter::Text => println!(
+                "{}",
+                render_text(
+                    &sorted,
+                    &options.project_root,
+                    options.line_mode,
+                    options.skip_full,
+                    terminal_width(),
+                )
+            ),
+            Reporter::TextSummary => println!(
+                "{}",
+                render_text_summary(&sorted, options.line_mode, terminal_width())
+            ),
+            Reporter::JsonSummary => {
+                let b = layout.to_json(&JsonSummary::from(merged, options.line_mode))?;
+                layout
+                    .write(&layout.merged_dir().join("coverage-summary.json"), b)
+                    .await?;
+            }
+            Reporter::Clover => {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis())
+                    .unwrap_or_default();
+                let b = render_clover(&sorted, &options.project_root, options.line_mode, timestamp);
+                layout
+                    .write(&layout.report_dir.join("clover.xml"), b)
+                    .await?;
+            }
+            Reporter::Coveralls => {
+                let mut sources = BTreeMap::new();
+                for path in sorted.keys() {
+                    match fs::read_to_string(path).await {
+                        Ok(s) => {
+                            sources.insert(path.to_string(), s);
+                        }
+                        Err(e) => {
+                            warn!("读取源码失败，coveralls 报告跳过这个文件 [{}] {}", path, e)
+                        }
+                    }
+                }
+                let payload =
+                    render_coveralls(&sorted, &sources, &options.project_root, options.line_mode);
+                let b = layout.to_json(&payload)?;
+                layout
+                    .write(&layout.report_dir.join("coveralls.json"), b)
+                    .await?;
+            }
+            Reporter::Codecov => {
+                let report = render_codecov(&sorted, &options.project_root, options.line_mode);
+                let b = layout.to_json(&report)?;
+                layout
+                    .write(&layout.report_dir.join("codecov.json"), b)
+                    .await?;
+            }
+            Reporter::Sonar => {
+                let b = render_sonar(&sorted, &options.project_root, options.line_mode);
+                layout
+                    .write(&layout.report_dir.join("sonar-coverage.xml"), b)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,27 +1,42 @@
+use crate::format::input::parse_coverage_input;
+use crate::format::MappingItem;
 use crate::fputil::{get_uri_resource, glob_abs};
+use crate::resolver::is_internal;
 use crate::statement::url_normalize;
 use crate::timer::Timer;
 use anyhow::{anyhow, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::path::Path;
 use std::rc::Rc;
-use tracing::{debug, info, instrument, warn};
+use std::str::FromStr;
+use tracing::{debug, info, instrument, trace, warn};
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ScriptCoverageRaw {
     pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub source: Option<String>,
     pub functions: Vec<FunctionCoverage>,
+    /// 计数只表示有没有执行过，输入格式本身没有执行次数的时候设置，比如 puppeteer 展开之后的区间
+    #[serde(skip)]
+    pub binary: bool,
 }
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ScriptCoverage {
     pub url: String,
     pub source: String,
     pub functions: Vec<FunctionCoverage>,
+    /// 规范化之后的完整 url，`url` 只保留了文件名
+    #[serde(skip)]
+    pub script_url: String,
+    /// 和 `ScriptCoverageRaw::binary` 一致
+    #[serde(skip)]
+    pub binary: bool,
 }
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FunctionCoverage {
     #[serde(rename = "functionName")]
     pub function_name: String,
@@ -30,7 +45,7 @@ pub struct FunctionCoverage {
     #[serde(rename = "isBlockCoverage")]
     pub is_block_coverage: bool,
 }
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CoverageRange {
     #[serde(rename = "startOffset")]
     pub start_offset: u32,
@@ -39,12 +54,42 @@ pub struct CoverageRange {
     pub count: u32,
 }
 
+/// 覆盖率计数的含义，按精确程度从低到高排序
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CountMode {
+    /// best-effort 覆盖率（`callCount: false`），0 / 1 只表示有没有执行过
+    Binary,
+    /// 函数级别的覆盖率（`detailed: false`），只有函数的调用次数，没有块的信息
+    Function,
+    /// 块级别的精确覆盖率
+    Block,
+}
+
+impl ScriptCoverage {
+    /// 根据输入格式和 `isBlockCoverage` 确定计数的含义，没有函数数据的时候返回 `None`
+    ///
+    /// 不能从计数的大小推断，精确覆盖率里面每个块都只执行一次的情况很常见
+    pub fn count_mode(&self) -> Option<CountMode> {
+        if self.functions.is_empty() {
+            return None;
+        }
+        Some(if self.binary {
+            CountMode::Binary
+        } else if self.functions.iter().any(|f| f.is_block_coverage) {
+            CountMode::Block
+        } else {
+            CountMode::Function
+        })
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct CoverRangeNodeRead {
-    children: Vec<CoverRangeNodeRead>,
+    pub children: Vec<CoverRangeNodeRead>,
     pub value: u32,
-    left: u32,
-    right: u32,
+    pub left: u32,
+    pub right: u32,
 }
 
 #[derive(Debug)]
@@ -74,8 +119,12 @@ pub fn build_coverage_range_tree(
     let _timer = Timer::new("构造覆盖率搜索树");
     let mut ranges = Vec::new();
     for x in script_fn_cov {
-        for x in x.ranges.iter() {
-            ranges.push(x);
+        if x.is_block_coverage {
+            ranges.extend(x.ranges.iter());
+        } else {
+            // 非块级覆盖率只有第一个区间是函数本身，它的计数是整个函数的调用次数，
+            // 后面的区间没有块的含义，不能参与嵌套
+            ranges.extend(x.ranges.first());
         }
     }
     ranges.sort_unstable_by(|a, b| {
@@ -127,35 +176,155 @@ pub fn read_only(root: Rc<RefCell<CoverRangeNode>>) -> CoverRangeNodeRead {
     r
 }
 pub fn find_root_value_only(root: &CoverRangeNodeRead, range: &CoverageRange) -> Option<u32> {
+    find_root_node(root, range).map(|n| n.value)
+}
+
+/// MappingItem 的计数，取包含整段生成代码 `[generated_column, last_generated_column]` 的最内层区间
+pub fn mapping_count(root: &CoverRangeNodeRead, m: &MappingItem) -> Option<u32> {
+    find_root_value_only(
+        root,
+        &CoverageRange {
+            start_offset: m.generated_column,
+            end_offset: m.last_generated_column,
+            count: 0,
+        },
+    )
+}
+
+/// 包含 `range` 的最内层区间
+pub fn find_root_node<'a>(
+    root: &'a CoverRangeNodeRead,
+    range: &CoverageRange,
+) -> Option<&'a CoverRangeNodeRead> {
     let left = root.left;
     let right = root.right;
     if range.start_offset < left || range.end_offset > right {
         return None;
     }
     for child in root.children.iter() {
-        if let Some(r) = find_root_value_only(child, range) {
+        if let Some(r) = find_root_node(child, range) {
             return Some(r);
         }
     }
-    Some(root.value)
+    Some(root)
+}
+
+/// 整个脚本的覆盖率搜索树，根节点覆盖整个脚本，计数是 0
+pub fn coverage_tree(sc: &ScriptCoverage) -> CoverRangeNodeRead {
+    let root = Rc::new(RefCell::new(CoverRangeNode::new(&CoverageRange {
+        start_offset: 0,
+        end_offset: sc.source.len() as u32,
+        count: 0,
+    })));
+    trace!("构造覆盖率搜索树");
+    build_coverage_range_tree(root.clone(), &sc.functions);
+    read_only(root)
+}
+
+/// node CJS 加载器包在模块代码外面的函数，老版本 node 统计的 offset 包含它
+pub const CJS_WRAPPER_PREFIX: &str =
+    "(function (exports, require, module, __filename, __dirname) { ";
+pub const CJS_WRAPPER_SUFFIX: &str = "\n});";
+
+/// 覆盖率 offset 相对于源码的偏移方式
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ModuleWrapper {
+    /// 根据最外层区间的长度判断有没有 CJS wrapper，和 c8 一样
+    #[default]
+    Auto,
+    /// ESM 或者浏览器脚本，offset 和源码一致
+    Esm,
+    /// offset 包含 node CJS wrapper
+    Cjs,
+    /// 自定义加载器在源码前面加的字符数
+    Custom(u32),
+}
+
+impl FromStr for ModuleWrapper {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(ModuleWrapper::Auto),
+            "esm" | "none" => Ok(ModuleWrapper::Esm),
+            "cjs" => Ok(ModuleWrapper::Cjs),
+            n => n
+                .parse()
+                .map(ModuleWrapper::Custom)
+                .map_err(|_| anyhow!("module wrapper 只能是 auto、esm、cjs 或者数字: {}", n)),
+        }
+    }
+}
+
+impl ModuleWrapper {
+    /// 需要从 offset 里面减掉的字符数
+    pub fn offset(&self, sc: &ScriptCoverage) -> u32 {
+        let prefix = CJS_WRAPPER_PREFIX.chars().count() as u32;
+        match self {
+            ModuleWrapper::Esm => 0,
+            ModuleWrapper::Cjs => prefix,
+            ModuleWrapper::Custom(n) => *n,
+            ModuleWrapper::Auto => {
+                // 源码本身带着 wrapper，offset 不需要修正
+                if sc.source.starts_with(CJS_WRAPPER_PREFIX) {
+                    return 0;
+                }
+                let end = sc
+                    .functions
+                    .iter()
+                    .flat_map(|f| f.ranges.iter())
+                    .map(|r| r.end_offset)
+                    .max()
+                    .unwrap_or_default();
+                let len = sc.source.chars().count() as u32;
+                let suffix = CJS_WRAPPER_SUFFIX.chars().count() as u32;
+                if end == len + prefix + suffix || end == len + prefix {
+                    debug!(url = sc.url, "检测到 CJS wrapper");
+                    prefix
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    /// 把 offset 修正成相对源码的位置，落在 wrapper 里面的部分会被截掉
+    pub fn apply(&self, sc: &mut ScriptCoverage) {
+        let offset = self.offset(sc);
+        if offset == 0 {
+            return;
+        }
+        let len = sc.source.chars().count() as u32;
+        for f in sc.functions.iter_mut() {
+            for r in f.ranges.iter_mut() {
+                r.start_offset = r.start_offset.saturating_sub(offset).min(len);
+                r.end_offset = r.end_offset.saturating_sub(offset).min(len);
+            }
+        }
+    }
 }
 
 pub async fn normalize_script_coverages(
     script_coverages: &Vec<ScriptCoverageRaw>,
     filters: &Vec<String>,
+    wrapper: ModuleWrapper,
 ) -> Result<Vec<ScriptCoverage>> {
     let mut r = Vec::new();
     for sc in script_coverages {
         let script_url = url_normalize(&sc.url);
+        if is_internal(&script_url) {
+            debug!("跳过 node 内部模块 {}", &script_url);
+            continue;
+        }
         let script_name = url_filename(&script_url);
-        if (filters.len() > 0 && filters.iter().find(|&f| script_url.contains(f)).is_some())
-            || filters.is_empty()
-        {
-            let v = if let Some(s) = sc.source.clone() {
+        if url_matches_filters(&script_url, filters) {
+            let mut v = if let Some(s) = sc.source.clone() {
                 ScriptCoverage {
                     url: script_name,
                     source: s.clone(),
                     functions: sc.functions.clone(),
+                    script_url: script_url.clone(),
+                    binary: sc.binary,
                 }
             } else {
                 let s = get_uri_resource(&script_url).await.map_err(|e| anyhow!("请求URL失败: {} {}", &script_url, e))?;
@@ -163,14 +332,22 @@ pub async fn normalize_script_coverages(
                     url: script_name,
                     source: s,
                     functions: sc.functions.clone(),
+                    script_url: script_url.clone(),
+                    binary: sc.binary,
                 }
             };
+            wrapper.apply(&mut v);
             r.push(v)
         }
     }
     Ok(r)
 }
 
+/// `--filters` 为空的时候全部保留，否则只保留 url 包含其中一个 filter 的脚本或者样式表
+pub fn url_matches_filters(url: &str, filters: &[String]) -> bool {
+    filters.is_empty() || filters.iter().any(|f| url.contains(f))
+}
+
 pub fn url_filename(u: &str) -> String {
     let s = u.split("?").next().unwrap_or_default();
     s.split("/").last().unwrap_or_default().to_string()
@@ -179,6 +356,7 @@ pub fn url_filename(u: &str) -> String {
 pub async fn collect_coverage_helper(
     path_pattern: &str,
     coverage_filters: &Vec<String>,
+    wrapper: ModuleWrapper,
 ) -> Result<HashMap<String, Vec<ScriptCoverage>>> {
     let _timer = Timer::new("收集本地覆盖率数据");
     let all_script_coverage_files = glob_abs(path_pattern)?;
@@ -188,16 +366,26 @@ pub async fn collect_coverage_helper(
     );
     let mut all_script_coverages = HashMap::new();
     for p in all_script_coverage_files {
-        debug!("处理覆盖率文件 {}", p.to_str().unwrap());
-        let s = std::fs::read_to_string(&p)?;
-        let sc_arr: Vec<ScriptCoverageRaw> =
-            serde_json::from_str(&s).map_err(|e| anyhow!("解析{:?}出错, {}", p.to_str(), e))?;
-        let sc_arr = normalize_script_coverages(&sc_arr, coverage_filters).await?;
+        let sc_arr = collect_coverage_file(&p, coverage_filters, wrapper).await?;
         all_script_coverages.insert(p.to_str().unwrap().to_string(), sc_arr);
     }
     Ok(all_script_coverages)
 }
 
+/// 读取并规范化一个覆盖率文件
+pub async fn collect_coverage_file(
+    p: &Path,
+    coverage_filters: &Vec<String>,
+    wrapper: ModuleWrapper,
+) -> Result<Vec<ScriptCoverage>> {
+    debug!("处理覆盖率文件 {}", p.to_string_lossy());
+    let s = std::fs::read_to_string(p)?;
+    let (format, sc_arr) =
+        parse_coverage_input(&s).map_err(|e| anyhow!("解析{:?}出错, {}", p.to_str(), e))?;
+    debug!(format = ?format, "覆盖率文件格式");
+    normalize_script_coverages(&sc_arr, coverage_filters, wrapper).await
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -217,4 +405,112 @@ mod test {
         dbg!(&root);
         Ok(())
     }
+
+    async fn load_cjs(wrapper: ModuleWrapper) -> Result<ScriptCoverage> {
+        let pattern = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/cjs/v8-coverage.json");
+        let r = collect_coverage_helper(pattern, &vec![], wrapper).await?;
+        Ok(r.into_values().next().unwrap().remove(0))
+    }
+
+    #[tokio::test]
+    async fn test_cjs_wrapper() -> Result<()> {
+        let source = include_str!("../../tests/cjs/main.js");
+        // 未执行的函数 f2 在源码里面的位置
+        let f2 = source.find("function f2").unwrap() as u32;
+        let sc = load_cjs(ModuleWrapper::Auto).await?;
+        assert_eq!(sc.source, source);
+        let range = &sc.functions[2].ranges[0];
+        assert_eq!(range.start_offset, f2);
+        assert_eq!(range.count, 0);
+        assert_eq!(sc.functions[0].ranges[0].end_offset, source.len() as u32);
+
+        let sc = load_cjs(ModuleWrapper::Cjs).await?;
+        assert_eq!(sc.functions[2].ranges[0].start_offset, f2);
+
+        let sc = load_cjs(ModuleWrapper::Esm).await?;
+        assert_eq!(
+            sc.functions[2].ranges[0].start_offset,
+            f2 + CJS_WRAPPER_PREFIX.len() as u32
+        );
+        Ok(())
+    }
+
+    fn function(is_block_coverage: bool, ranges: &[(u32, u32, u32)]) -> FunctionCoverage {
+        FunctionCoverage {
+            function_name: "f".to_string(),
+            ranges: ranges
+                .iter()
+                .map(|&(start_offset, end_offset, count)| CoverageRange {
+                    start_offset,
+                    end_offset,
+                    count,
+                })
+                .collect(),
+            is_block_coverage,
+        }
+    }
+
+    #[test]
+    fn test_non_block_coverage() {
+        let root = Rc::new(RefCell::new(CoverRangeNode::new(&CoverageRange {
+            start_offset: 0,
+            end_offset: 100,
+            count: 0,
+        })));
+        let functions = vec![
+            function(false, &[(0, 100, 1)]),
+            // 非块级覆盖率后面的区间会被忽略
+            function(false, &[(10, 50, 3), (20, 30, 0)]),
+        ];
+        build_coverage_range_tree(root.clone(), &functions);
+        let tree = read_only(root);
+        let at = |start_offset, end_offset| {
+            find_root_value_only(
+                &tree,
+                &CoverageRange {
+                    start_offset,
+                    end_offset,
+                    count: 0,
+                },
+            )
+        };
+        assert_eq!(at(22, 25), Some(3));
+        assert_eq!(at(60, 70), Some(1));
+
+        let sc = |functions| ScriptCoverage {
+            url: "a.js".to_string(),
+            source: "".to_string(),
+            functions,
+            script_url: "".to_string(),
+            binary: false,
+        };
+        assert_eq!(sc(vec![]).count_mode(), None);
+        assert_eq!(sc(functions).count_mode(), Some(CountMode::Function));
+        // 所有块都只执行了一次的精确覆盖率
+        assert_eq!(
+            sc(vec![function(true, &[(0, 100, 1), (10, 20, 1)])]).count_mode(),
+            Some(CountMode::Block)
+        );
+        assert_eq!(
+            sc(vec![function(false, &[(0, 100, 1)])]).count_mode(),
+            Some(CountMode::Function)
+        );
+        let binary = ScriptCoverage {
+            binary: true,
+            ..sc(vec![function(true, &[(0, 100, 1)])])
+        };
+        assert_eq!(binary.count_mode(), Some(CountMode::Binary));
+    }
+
+    #[test]
+    fn test_module_wrapper_parse() {
+        assert_eq!("auto".parse::<ModuleWrapper>().unwrap(), ModuleWrapper::Auto);
+        assert_eq!("esm".parse::<ModuleWrapper>().unwrap(), ModuleWrapper::Esm);
+        assert_eq!("cjs".parse::<ModuleWrapper>().unwrap(), ModuleWrapper::Cjs);
+        assert_eq!(
+            "12".parse::<ModuleWrapper>().unwrap(),
+            ModuleWrapper::Custom(12)
+        );
+        assert!("amd".parse::<ModuleWrapper>().is_err());
+    }
 }
@@ -0,0 +1,98 @@
+use crate::format::istanbul::IstanbulCov;
+use crate::format::line::{branch_coverage, line_coverage, LineMode};
+use crate::format::{relative_path, xml_escape};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+/// SonarQube 的 generic test coverage 格式
+///
+/// <https://docs.sonarsource.com/sonarqube/latest/analyzing-source-code/test-coverage/generic-test-data/>
+pub fn render_sonar(
+    merged: &BTreeMap<&str, &IstanbulCov>,
+    project_root: &Path,
+    mode: LineMode,
+) -> String {
+    let mut out = String::from("<coverage version=\"1\">\n");
+    for (path, ic) in merged {
+        let branches = branch_coverage(ic);
+        let mut lines: BTreeMap<u32, bool> = line_coverage(ic, mode)
+            .into_iter()
+            .map(|(line, count)| (line, count > 0))
+            .collect();
+        // 只有分支没有语句的行也要输出，否则 sonar 会忽略这一行的分支
+        for (line, (_, covered)) in branches.iter() {
+            lines.entry(*line).or_insert(*covered > 0);
+        }
+        writeln!(
+            out,
+            "  <file path=\"{}\">",
+            xml_escape(&relative_path(path, project_root))
+        )
+        .unwrap();
+        for (line, covered) in lines {
+            write!(
+                out,
+                "    <lineToCover lineNumber=\"{}\" covered=\"{}\"",
+                line, covered
+            )
+            .unwrap();
+            if let Some((total, covered)) = branches.get(&line).filter(|(t, _)| *t > 0) {
+                write!(
+                    out,
+                    " branchesToCover=\"{}\" coveredBranches=\"{}\"",
+                    total, covered
+                )
+                .unwrap();
+            }
+            out.push_str("/>\n");
+        }
+        out.push_str("  </file>\n");
+    }
+    out.push_str("</coverage>\n");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::istanbul::{BranchMap, Position, StatementMap};
+
+    #[test]
+    fn test_render_sonar() {
+        let mut ic = IstanbulCov::default();
+        for (key, line, count) in [("0", 1, 1), ("1", 2, 0)] {
+            let pos = Position { line, column: 0 };
+            ic.statement_map.insert(
+                key.to_string(),
+                StatementMap {
+                    start: pos.clone(),
+                    end: pos,
+                    skip: None,
+                },
+            );
+            ic.s.insert(key.to_string(), count);
+        }
+        ic.branch_map.insert(
+            "0".to_string(),
+            BranchMap {
+                line: 1,
+                r#type: "if".to_string(),
+                locations: vec![],
+            },
+        );
+        ic.b.insert("0".to_string(), vec![1, 0]);
+        let merged = BTreeMap::from([("/p/src/a&b.js", &ic)]);
+        let xml = render_sonar(&merged, Path::new("/p"), LineMode::Max);
+        assert_eq!(
+            xml,
+            r#"<coverage version="1">
+  <file path="src/a&amp;b.js">
+    <lineToCover lineNumber="1" covered="true" branchesToCover="2" coveredBranches="1"/>
+    <lineToCover lineNumber="2" covered="false"/>
+  </file>
+</coverage>
+"#
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use crate::format::istanbul::IstanbulCov;
+use crate::format::line::{line_coverage, LineMode};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Metric {
+    pub total: u32,
+    pub covered: u32,
+    pub skipped: u32,
+    pub pct: f64,
+}
+
+impl Metric {
+    pub fn new(total: u32, covered: u32) -> Self {
+        let mut m = Metric {
+            total,
+            covered,
+            skipped: 0,
+            pct: 0.0,
+        };
+        m.update_pct();
+        m
+    }
+
+    /// 和 istanbul 一样保留两位小数（向下取整），没有数据的时候算 100%
+    fn update_pct(&mut self) {
+        self.pct = if self.total == 0 {
+            100.0
+        } else {
+            (100_000.0 * self.covered as f64 / self.total as f64 / 10.0).floor() / 100.0
+        };
+    }
+
+    /// 每一项是 (是否执行过, 是否被 ignore 注释排除)
+    ///
+    /// 和 istanbul 一样，没有执行过的排除项计入 skipped，同时也算作已覆盖
+    fn from_items(items: impl Iterator<Item = (bool, bool)>) -> Self {
+        let mut m = Metric::default();
+        for (covered, skipped) in items {
+            m.total += 1;
+            if covered || skipped {
+                m.covered += 1;
+            }
+            if !covered && skipped {
+                m.skipped += 1;
+            }
+        }
+        m.update_pct();
+        m
+    }
+
+    pub fn merge(&mut self, other: &Metric) {
+        self.total += other.total;
+        self.covered += other.covered;
+        self.skipped += other.skipped;
+        self.update_pct();
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.covered == self.total
+    }
+}
+
+/// 单个文件或者全部文件的覆盖率汇总，字段和 istanbul 的 `coverage-summary.json` 一致
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct CoverageSummary {
+    pub lines: Metric,
+    pub statements: Metric,
+    pub functions: Metric,
+    pub branches: Metric,
+}
+
+impl CoverageSummary {
+    pub fn from(ic: &IstanbulCov, mode: LineMode) -> Self {
+        let lines = line_coverage(ic, mode);
+        CoverageSummary {
+            lines: Metric::new(
+                lines.len() as u32,
+                lines.values().filter(|&&c| c > 0).count() as u32,
+            ),
+            statements: Metric::from_items(ic.s.iter().map(|(k, &c)| {
+                let skip = ic.statement_map.get(k).and_then(|s| s.skip);
+                (c > 0, skip == Some(true))
+            })),
+            functions: Metric::from_items(ic.f.iter().map(|(k, &c)| {
+                let skip = ic.fn_map.get(k).and_then(|f| f.skip);
+                (c > 0, skip == Some(true))
+            })),
+            branches: Metric::from_items(ic.b.iter().flat_map(|(k, counts)| {
+                let locations = ic.branch_map.get(k).map(|b| b.locations.as_slice());
+                counts.iter().enumerate().map(move |(i, &c)| {
+                    let skip = locations.and_then(|l| l.get(i)).and_then(|l| l.skip);
+                    (c > 0, skip == Some(true))
+                })
+            })),
+        }
+    }
+
+    pub fn merge(&mut self, other: &CoverageSummary) {
+        self.lines.merge(&other.lines);
+        self.statements.merge(&other.statements);
+        self.functions.merge(&other.functions);
+        self.branches.merge(&other.branches);
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.lines.is_full()
+            && self.statements.is_full()
+            && self.functions.is_full()
+            && self.branches.is_full()
+    }
+}
+
+/// istanbul `json-summary` 报告，`total` 在最前面，后面按文件路径排序
+#[derive(Debug, Default)]
+pub struct JsonSummary {
+    pub total: CoverageSummary,
+    pub files: BTreeMap<String, CoverageSummary>,
+}
+
+impl JsonSummary {
+    pub fn from(merged: &HashMap<String, IstanbulCov>, mode: LineMode) -> Self {
+        let mut r = JsonSummary::default();
+        for (path, ic) in merged {
+            let summary = CoverageSummary::from(ic, mode);
+            r.total.merge(&summary);
+            r.files.insert(path.clone(), summary);
+        }
+        r
+    }
+}
+
+impl Serialize for JsonSummary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.files.len() + 1))?;
+        map.serialize_entry("total", &self.total)?;
+        for (k, v) in &self.files {
+            map.serialize_entry(k, v)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::istanbul::{Position, StatementMap};
+
+    #[test]
+    fn test_metric_pct() {
+        assert_eq!(Metric::new(0, 0).pct, 100.0);
+        assert_eq!(Metric::new(3, 1).pct, 33.33);
+        assert_eq!(Metric::new(3, 2).pct, 66.66);
+        let mut m = Metric::new(3, 2);
+        m.merge(&Metric::new(1, 1));
+        assert_eq!(m, Metric::new(4, 3));
+        assert_eq!(m.pct, 75.0);
+    }
+
+    #[test]
+    fn test_json_summary() {
+        let pos = |line| Position { line, column: 0 };
+        let mut ic = IstanbulCov {
+            path: "/p/a.js".to_string(),
+            ..Default::default()
+        };
+        for (i, count) in [1, 0].iter().enumerate() {
+            ic.statement_map.insert(
+                i.to_string(),
+                StatementMap {
+                    start: pos(i as u32 + 1),
+                    end: pos(i as u32 + 1),
+                    skip: None,
+                },
+            );
+            ic.s.insert(i.to_string(), *count);
+        }
+        ic.b.insert("0".to_string(), vec![1, 0, 0]);
+        let merged = HashMap::from([("/p/a.js".to_string(), ic)]);
+        let v = serde_json::to_value(JsonSummary::from(&merged, LineMode::Max)).unwrap();
+        let s = serde_json::to_string(&JsonSummary::from(&merged, LineMode::Max)).unwrap();
+        assert!(s.starts_with(r#"{"total":{"lines":"#));
+        assert_eq!(
+            v["total"]["statements"],
+            serde_json::json!({"total": 2, "covered": 1, "skipped": 0, "pct": 50.0})
+        );
+        assert_eq!(v["/p/a.js"]["branches"]["covered"], 1);
+        assert_eq!(v["/p/a.js"]["branches"]["pct"], 33.33);
+        assert_eq!(v["/p/a.js"]["functions"]["pct"], 100.0);
+    }
+
+    #[test]
+    fn test_skipped() {
+        let pos = |line| Position { line, column: 0 };
+        let mut ic = IstanbulCov::default();
+        for (i, (count, skip)) in [(1, None), (0, Some(true)), (0, None)].iter().enumerate() {
+            ic.statement_map.insert(
+                i.to_string(),
+                StatementMap {
+                    start: pos(i as u32 + 1),
+                    end: pos(i as u32 + 1),
+                    skip: *skip,
+                },
+            );
+            ic.s.insert(i.to_string(), *count);
+        }
+        let summary = CoverageSummary::from(&ic, LineMode::Max);
+        assert_eq!(
+            summary.statements,
+            Metric {
+                total: 3,
+                covered: 2,
+                skipped: 1,
+                pct: 66.66
+            }
+        );
+        // 和 istanbul 一样，行覆盖率不检查 skip
+        assert_eq!(summary.lines, Metric::new(3, 1));
+    }
+}
@@ -0,0 +1,270 @@
+use crate::format::istanbul::IstanbulCov;
+use crate::format::line::{line_coverage, LineMode};
+use crate::format::relative_path;
+use crate::format::summary::{CoverageSummary, Metric};
+use std::collections::BTreeMap;
+use std::env;
+use std::path::Path;
+
+const DEFAULT_WIDTH: usize = 80;
+const PCT_WIDTH: usize = 9;
+const NAME_MIN_WIDTH: usize = 9;
+const UNCOVERED_TITLE: &str = "Uncovered Line #s";
+
+/// 终端宽度，CI 里面一般没有 tty，只能读 `COLUMNS` 环境变量
+pub fn terminal_width() -> usize {
+    env::var("COLUMNS")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .filter(|&c| c > 0)
+        .unwrap_or(DEFAULT_WIDTH)
+}
+
+/// 连续的未覆盖行合并成区间，比如 `3-5,9`
+pub fn uncovered_lines(ic: &IstanbulCov, mode: LineMode) -> String {
+    let mut r: Vec<(u32, u32)> = vec![];
+    for (line, count) in line_coverage(ic, mode) {
+        if count > 0 {
+            continue;
+        }
+        match r.last_mut() {
+            Some((_, end)) if *end + 1 == line => *end = line,
+            _ => r.push((line, line)),
+        }
+    }
+    r.iter()
+        .map(|(s, e)| {
+            if s == e {
+                s.to_string()
+            } else {
+                format!("{}-{}", s, e)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn pct(m: &Metric) -> String {
+    format!("{}", m.pct)
+}
+
+/// 宽度不够的时候保留字符串的结尾，前面用 `...` 代替
+fn fit_start(s: &str, width: usize) -> String {
+    let n = s.chars().count();
+    if n <= width {
+        return s.to_string();
+    }
+    if width <= 3 {
+        return s.chars().skip(n - width).collect();
+    }
+    format!("...{}", s.chars().skip(n - width + 3).collect::<String>())
+}
+
+/// 宽度不够的时候截断字符串的结尾
+fn fit_end(s: &str, width: usize) -> String {
+    let n = s.chars().count();
+    if n <= width {
+        return s.to_string();
+    }
+    if width <= 3 {
+        return s.chars().take(width).collect();
+    }
+    format!("{}...", s.chars().take(width - 3).collect::<String>())
+}
+
+/// 对应 istanbul 的 `text` reporter
+pub fn render_text(
+    merged: &BTreeMap<&str, &IstanbulCov>,
+    root: &Path,
+    mode: LineMode,
+    skip_full: bool,
+    width: usize,
+) -> String {
+    let mut total = CoverageSummary::default();
+    let mut rows = vec![];
+    for (path, ic) in merged {
+        let summary = CoverageSummary::from(ic, mode);
+        total.merge(&summary);
+        if skip_full && summary.is_full() {
+            continue;
+        }
+        rows.push((
+            relative_path(path, root),
+            summary,
+            uncovered_lines(ic, mode),
+        ));
+    }
+
+    // 文件名 + 4 个百分比 + 未覆盖行，列之间用 ` | ` 分隔
+    let fixed = (PCT_WIDTH + 3) * 4 + 3;
+    let name_width = rows
+        .iter()
+        .map(|(n, _, _)| n.chars().count() + 1)
+        .chain([NAME_MIN_WIDTH])
+        .max()
+        .unwrap_or(NAME_MIN_WIDTH)
+        .min(
+            width
+                .saturating_sub(fixed + UNCOVERED_TITLE.len())
+                .max(NAME_MIN_WIDTH),
+        );
+    let uncovered_width = width
+        .saturating_sub(name_width + fixed)
+        .max(UNCOVERED_TITLE.len());
+
+    let sep = format!(
+        "{}|{}|{}|{}|{}|{}",
+        "-".repeat(name_width + 1),
+        "-".repeat(PCT_WIDTH + 2),
+        "-".repeat(PCT_WIDTH + 2),
+        "-".repeat(PCT_WIDTH + 2),
+        "-".repeat(PCT_WIDTH + 2),
+        "-".repeat(uncovered_width + 1),
+    );
+    let row = |name: &str, s: &CoverageSummary, uncovered: &str| -> String {
+        format!(
+            "{:<nw$} | {:>pw$} | {:>pw$} | {:>pw$} | {:>pw$} | {}",
+            fit_start(name, name_width),
+            pct(&s.statements),
+            pct(&s.branches),
+            pct(&s.functions),
+            pct(&s.lines),
+            fit_end(uncovered, uncovered_width),
+            nw = name_width,
+            pw = PCT_WIDTH,
+        )
+    };
+
+    let mut out = vec![sep.clone()];
+    out.push(format!(
+        "{:<nw$} | {:>pw$} | {:>pw$} | {:>pw$} | {:>pw$} | {}",
+        "File",
+        "% Stmts",
+        "% Branch",
+        "% Funcs",
+        "% Lines",
+        UNCOVERED_TITLE,
+        nw = name_width,
+        pw = PCT_WIDTH,
+    ));
+    out.push(sep.clone());
+    out.push(row("All files", &total, ""));
+    for (name, summary, uncovered) in rows.iter() {
+        out.push(row(&format!(" {}", name), summary, uncovered));
+    }
+    out.push(sep);
+    out.iter()
+        .map(|l| l.trim_end().to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// 对应 istanbul 的 `text-summary` reporter
+pub fn render_text_summary(
+    merged: &BTreeMap<&str, &IstanbulCov>,
+    mode: LineMode,
+    width: usize,
+) -> String {
+    let mut total = CoverageSummary::default();
+    for ic in merged.values() {
+        total.merge(&CoverageSummary::from(ic, mode));
+    }
+    let title = " Coverage summary ";
+    let side = width.saturating_sub(title.len()) / 2;
+    let line =
+        |name: &str, m: &Metric| format!("{:<13}: {}% ( {}/{} )", name, m.pct, m.covered, m.total);
+    [
+        format!(
+            "{}{}{}",
+            "=".repeat(side),
+            title,
+            "=".repeat(width.saturating_sub(side + title.len()))
+        ),
+        line("Statements", &total.statements),
+        line("Branches", &total.branches),
+        line("Functions", &total.functions),
+        line("Lines", &total.lines),
+        "=".repeat(width),
+    ]
+    .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::istanbul::{Position, StatementMap};
+
+    fn cov(path: &str, counts: &[(u32, u32)]) -> IstanbulCov {
+        let mut ic = IstanbulCov {
+            path: path.to_string(),
+            ..Default::default()
+        };
+        for (i, (line, count)) in counts.iter().enumerate() {
+            ic.statement_map.insert(
+                i.to_string(),
+                StatementMap {
+                    start: Position {
+                        line: *line,
+                        column: 0,
+                    },
+                    end: Position {
+                        line: *line,
+                        column: 10,
+                    },
+                    skip: None,
+                },
+            );
+            ic.s.insert(i.to_string(), *count);
+        }
+        ic
+    }
+
+    #[test]
+    fn test_uncovered_lines() {
+        let ic = cov("/a.js", &[(1, 1), (2, 0), (3, 0), (4, 0), (5, 2), (7, 0)]);
+        assert_eq!(uncovered_lines(&ic, LineMode::Max), "2-4,7");
+    }
+
+    #[test]
+    fn test_render_text() {
+        let a = cov("/p/src/a.js", &[(1, 1), (2, 0)]);
+        let b = cov("/p/src/b.js", &[(1, 1)]);
+        let merged = BTreeMap::from([("/p/src/a.js", &a), ("/p/src/b.js", &b)]);
+        let text = render_text(&merged, Path::new("/p"), LineMode::Max, false, 100);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[1].starts_with("File"));
+        assert!(lines[3].starts_with("All files"));
+        assert!(lines[3].contains("66.66"));
+        assert!(lines[4].starts_with(" src/a.js"));
+        assert!(lines[4].ends_with("| 2"));
+        assert!(lines.iter().all(|l| l.chars().count() <= 100));
+
+        let text = render_text(&merged, Path::new("/p"), LineMode::Max, true, 100);
+        assert!(!text.contains("b.js"));
+
+        let summary = render_text_summary(&merged, LineMode::Max, 80);
+        assert!(summary.contains("Statements   : 66.66% ( 2/3 )"));
+        assert!(summary.lines().all(|l| l.chars().count() <= 80));
+    }
+
+    #[test]
+    fn test_render_text_narrow() {
+        let path = format!("/p/src/{}/a.js", "very-long-directory-name".repeat(3));
+        let ic = cov(&path, &[(1, 1), (2, 0), (3, 0)]);
+        let merged = BTreeMap::from([(path.as_str(), &ic)]);
+        let text = render_text(&merged, Path::new("/p"), LineMode::Max, false, 80);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.iter().all(|l| l.chars().count() <= 80), "{}", text);
+        // 文件名截断的时候保留结尾，未覆盖行的列至少和标题一样宽
+        assert!(lines[4].starts_with("...name/a.js |"));
+        assert!(lines[1].ends_with("| Uncovered Line #s"));
+        assert!(lines[4].ends_with("| 2-3"));
+    }
+
+    #[test]
+    fn test_fit() {
+        assert_eq!(fit_start("abcdef", 5), "...ef");
+        assert_eq!(fit_end("abcdef", 5), "ab...");
+        assert_eq!(fit_end("abc", 5), "abc");
+    }
+}
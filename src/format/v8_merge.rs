@@ -0,0 +1,357 @@
+use crate::format::script_coverage::{
+    CoverageRange, FunctionCoverage, ScriptCoverage, ScriptCoverageRaw,
+};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::{instrument, warn};
+
+/// 函数内部嵌套的覆盖率区间，children 按 start 排序而且互不重叠
+#[derive(Debug, Clone, PartialEq)]
+struct RangeTree {
+    start: u32,
+    end: u32,
+    count: u32,
+    children: Vec<RangeTree>,
+}
+
+impl RangeTree {
+    fn leaf(start: u32, end: u32, count: u32) -> Self {
+        RangeTree {
+            start,
+            end,
+            count,
+            children: vec![],
+        }
+    }
+
+    /// v8 的区间按先序排列，第一个是函数本身
+    fn from_function(f: &FunctionCoverage) -> Option<Self> {
+        let mut ranges: Vec<&CoverageRange> = if f.is_block_coverage {
+            f.ranges.iter().collect()
+        } else {
+            f.ranges.first().into_iter().collect()
+        };
+        ranges.sort_by_key(|r| (r.start_offset, Reverse(r.end_offset)));
+        let mut i = 0;
+        let tree = Self::build(&ranges, &mut i)?;
+        if i < ranges.len() {
+            warn!(
+                function = f.function_name,
+                "有 {} 个区间不在函数范围内，已忽略",
+                ranges.len() - i
+            );
+        }
+        Some(tree)
+    }
+
+    fn build(ranges: &[&CoverageRange], i: &mut usize) -> Option<Self> {
+        let r = ranges.get(*i)?;
+        *i += 1;
+        let mut node = RangeTree::leaf(r.start_offset, r.end_offset, r.count);
+        while let Some(next) = ranges.get(*i) {
+            if next.start_offset < node.start || next.end_offset > node.end {
+                break;
+            }
+            node.children.extend(Self::build(ranges, i));
+        }
+        Some(node)
+    }
+
+    fn split(self, x: u32) -> (RangeTree, RangeTree) {
+        let mut left = RangeTree::leaf(self.start, x, self.count);
+        let mut right = RangeTree::leaf(x, self.end, self.count);
+        for c in self.children {
+            if c.end <= x {
+                left.children.push(c);
+            } else if c.start >= x {
+                right.children.push(c);
+            } else {
+                let (l, r) = c.split(x);
+                left.children.push(l);
+                right.children.push(r);
+            }
+        }
+        (left, right)
+    }
+
+    /// 在所有落在区间内部的边界处切开
+    fn pieces(self, bounds: &BTreeSet<u32>) -> Vec<RangeTree> {
+        let mut r = vec![];
+        let mut rest = self;
+        let inner: Vec<u32> = bounds.range(rest.start + 1..rest.end).copied().collect();
+        for x in inner {
+            let (l, right) = rest.split(x);
+            r.push(l);
+            rest = right;
+        }
+        r.push(rest);
+        r
+    }
+
+    /// 合并范围完全相同的几棵树，计数相加
+    ///
+    /// 子区间先在所有输入的边界处切开，这样不同输入的子区间要么相同要么不相交；
+    /// 某个输入在某一段没有子区间的时候，用它父区间的计数补齐
+    fn merge(trees: Vec<RangeTree>) -> RangeTree {
+        let (start, end) = (trees[0].start, trees[0].end);
+        let counts: Vec<u32> = trees.iter().map(|t| t.count).collect();
+        let bounds: BTreeSet<u32> = trees
+            .iter()
+            .flat_map(|t| t.children.iter().flat_map(|c| [c.start, c.end]))
+            .collect();
+
+        let mut slots: BTreeMap<(u32, u32), Vec<Option<RangeTree>>> = BTreeMap::new();
+        for (i, t) in trees.into_iter().enumerate() {
+            for c in t.children {
+                for p in c.pieces(&bounds) {
+                    let key = (p.start, p.end);
+                    slots.entry(key).or_insert_with(|| vec![None; counts.len()])[i] = Some(p);
+                }
+            }
+        }
+
+        let mut node = RangeTree::leaf(start, end, counts.iter().sum());
+        for ((s, e), inputs) in slots {
+            let inputs = inputs
+                .into_iter()
+                .zip(counts.iter())
+                .map(|(p, &count)| p.unwrap_or(RangeTree::leaf(s, e, count)))
+                .collect();
+            node.children.push(RangeTree::merge(inputs));
+        }
+        node.normalize();
+        node
+    }
+
+    /// 去掉和父区间计数相同的子区间，合并相邻而且计数相同的兄弟区间
+    fn normalize(&mut self) {
+        let mut children: Vec<RangeTree> = vec![];
+        for c in std::mem::take(&mut self.children) {
+            if c.count == self.count {
+                children.extend(c.children);
+            } else {
+                children.push(c);
+            }
+        }
+        children.sort_by_key(|c| c.start);
+        for c in children {
+            match self.children.last_mut() {
+                Some(last) if last.end == c.start && last.count == c.count => {
+                    last.end = c.end;
+                    last.children.extend(c.children);
+                    last.normalize();
+                }
+                _ => self.children.push(c),
+            }
+        }
+    }
+
+    fn to_ranges(&self, r: &mut Vec<CoverageRange>) {
+        r.push(CoverageRange {
+            start_offset: self.start,
+            end_offset: self.end,
+            count: self.count,
+        });
+        for c in self.children.iter() {
+            c.to_ranges(r);
+        }
+    }
+}
+
+/// 合并同一个脚本在多次运行中的函数覆盖率，函数按最外层区间匹配
+///
+/// 精确的块级覆盖率里面没有执行过的函数也是 `isBlockCoverage: false`，计数为 0，可以直接合并；
+/// 其他没有块信息的函数计数没法分配到块上，和块级覆盖率一起合并会把没执行的块算成执行过，
+/// 这种情况只保留块级覆盖率
+pub fn merge_functions(lists: Vec<Vec<FunctionCoverage>>) -> Vec<FunctionCoverage> {
+    let mut groups: BTreeMap<(u32, Reverse<u32>), Vec<FunctionCoverage>> = BTreeMap::new();
+    for f in lists.into_iter().flatten() {
+        let Some(root) = f.ranges.first() else {
+            continue;
+        };
+        groups
+            .entry((root.start_offset, Reverse(root.end_offset)))
+            .or_default()
+            .push(f);
+    }
+    groups
+        .into_values()
+        .map(|mut fns| {
+            if fns.iter().any(|f| f.is_block_coverage) {
+                let (precise, dropped): (Vec<_>, Vec<_>) = fns
+                    .into_iter()
+                    .partition(|f| f.is_block_coverage || f.ranges.iter().all(|r| r.count == 0));
+                if !dropped.is_empty() {
+                    warn!(
+                        function = dropped[0].function_name,
+                        "有 {} 次运行只有函数级别的覆盖率，和块级覆盖率不能合并，已忽略",
+                        dropped.len()
+                    );
+                }
+                fns = precise;
+            }
+            if fns.len() == 1 {
+                return fns.remove(0);
+            }
+            let trees: Vec<RangeTree> = fns.iter().filter_map(RangeTree::from_function).collect();
+            let tree = RangeTree::merge(trees);
+            let mut ranges = vec![];
+            tree.to_ranges(&mut ranges);
+            FunctionCoverage {
+                function_name: fns[0].function_name.clone(),
+                is_block_coverage: fns.iter().any(|f| f.is_block_coverage)
+                    || !tree.children.is_empty(),
+                ranges,
+            }
+        })
+        .collect()
+}
+
+fn merge_by<T, K: Ord>(
+    items: Vec<T>,
+    key: impl Fn(&T) -> K,
+    functions: impl Fn(&mut T) -> &mut Vec<FunctionCoverage>,
+    binary: impl Fn(&mut T) -> &mut bool,
+) -> Vec<T> {
+    let mut groups: BTreeMap<K, (T, Vec<Vec<FunctionCoverage>>)> = BTreeMap::new();
+    for mut item in items {
+        let fns = std::mem::take(functions(&mut item));
+        let is_binary = *binary(&mut item);
+        let group = groups.entry(key(&item)).or_insert_with(|| (item, vec![]));
+        // 有一份只有 0 / 1 计数的时候，合并之后的计数也只表示有没有执行过
+        *binary(&mut group.0) |= is_binary;
+        group.1.push(fns);
+    }
+    groups
+        .into_values()
+        .map(|(mut item, lists)| {
+            *functions(&mut item) = merge_functions(lists);
+            item
+        })
+        .collect()
+}
+
+/// 合并多个测试里面相同的脚本，url 相同但是源码不同的脚本不会合并
+#[instrument(skip_all)]
+pub fn merge_script_coverages(scs: Vec<ScriptCoverage>) -> Vec<ScriptCoverage> {
+    merge_by(
+        scs,
+        |sc| (sc.url.clone(), sc.source.clone()),
+        |sc| &mut sc.functions,
+        |sc| &mut sc.binary,
+    )
+}
+
+/// `merge-v8` 子命令使用，保留原始的 url
+#[instrument(skip_all)]
+pub fn merge_raw_script_coverages(scs: Vec<ScriptCoverageRaw>) -> Vec<ScriptCoverageRaw> {
+    merge_by(
+        scs,
+        |sc| (sc.url.clone(), sc.source.clone()),
+        |sc| &mut sc.functions,
+        |sc| &mut sc.binary,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn function(ranges: &[(u32, u32, u32)]) -> FunctionCoverage {
+        FunctionCoverage {
+            function_name: "f".to_string(),
+            ranges: ranges
+                .iter()
+                .map(|&(start_offset, end_offset, count)| CoverageRange {
+                    start_offset,
+                    end_offset,
+                    count,
+                })
+                .collect(),
+            is_block_coverage: ranges.len() > 1,
+        }
+    }
+
+    fn ranges(f: &FunctionCoverage) -> Vec<(u32, u32, u32)> {
+        f.ranges
+            .iter()
+            .map(|r| (r.start_offset, r.end_offset, r.count))
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_same_shape() {
+        let r = merge_functions(vec![
+            vec![function(&[(0, 100, 1), (10, 20, 0)])],
+            vec![function(&[(0, 100, 2), (10, 20, 1)])],
+        ]);
+        assert_eq!(ranges(&r[0]), vec![(0, 100, 3), (10, 20, 1)]);
+    }
+
+    #[test]
+    fn test_merge_partial_overlap() {
+        // 第一次运行 [10, 30) 没有执行，第二次运行 [20, 40) 没有执行
+        let r = merge_functions(vec![
+            vec![function(&[(0, 100, 1), (10, 30, 0)])],
+            vec![function(&[(0, 100, 1), (20, 40, 0)])],
+        ]);
+        assert_eq!(
+            ranges(&r[0]),
+            vec![(0, 100, 2), (10, 20, 1), (20, 30, 0), (30, 40, 1)]
+        );
+    }
+
+    #[test]
+    fn test_merge_nested_and_missing() {
+        let r = merge_functions(vec![
+            vec![
+                function(&[(0, 100, 1), (10, 50, 0), (20, 30, 0)]),
+                function(&[(60, 80, 0)]),
+            ],
+            vec![function(&[(0, 100, 0)]), function(&[(60, 80, 2)])],
+        ]);
+        assert_eq!(r.len(), 2);
+        // 第二次运行没有执行这个函数，没有块信息，计数用整个函数的计数补齐
+        assert_eq!(ranges(&r[0]), vec![(0, 100, 1), (10, 50, 0)]);
+        assert!(r[0].is_block_coverage);
+        assert_eq!(ranges(&r[1]), vec![(60, 80, 2)]);
+        assert!(!r[1].is_block_coverage);
+    }
+
+    #[test]
+    fn test_merge_mixed_precision() {
+        // 第二次运行只有函数级别的计数，不知道 [10, 50) 有没有执行过
+        let r = merge_functions(vec![
+            vec![function(&[(0, 100, 1), (10, 50, 0)])],
+            vec![function(&[(0, 100, 3)])],
+            vec![function(&[(0, 100, 2), (10, 50, 1)])],
+        ]);
+        assert_eq!(ranges(&r[0]), vec![(0, 100, 3), (10, 50, 1)]);
+        assert!(r[0].is_block_coverage);
+    }
+
+    #[test]
+    fn test_merge_script_coverages() {
+        let sc = |url: &str, source: &str, count| ScriptCoverage {
+            url: url.to_string(),
+            source: source.to_string(),
+            functions: vec![function(&[(0, 10, count)])],
+            script_url: "".to_string(),
+            binary: false,
+        };
+        let r = merge_script_coverages(vec![
+            sc("a.js", "a", 1),
+            ScriptCoverage {
+                binary: true,
+                ..sc("a.js", "a", 2)
+            },
+            sc("a.js", "b", 5),
+            sc("b.js", "a", 1),
+        ]);
+        assert_eq!(r.len(), 3);
+        assert_eq!(r[0].functions[0].ranges[0].count, 3);
+        assert!(r[0].binary);
+        assert_eq!(r[1].functions[0].ranges[0].count, 5);
+        assert!(!r[1].binary);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::format::path_normalize;
+use crate::resolver::Resolver;
 use crate::timer::Timer;
 use anyhow::{anyhow, Result};
 use glob::glob;
@@ -7,29 +8,88 @@ use sha1::{Digest, Sha1};
 use std::env;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use tokio::fs;
 use tracing::{instrument, trace};
-use url::Url;
 
-const NOT_EXIST_DIR: &'static str = "/abc/def/xyz/817457891234/";
 #[instrument]
 pub fn is_legal_source_path(s: &str) -> bool {
     if s.starts_with("external script ")
         || s.starts_with("webpack:")
         || s.starts_with("http:/")
         || s.starts_with("https:/")
-        || s.contains("node_modules")
     {
         return false;
     }
-    let path = PathBuf::from(NOT_EXIST_DIR).join(s);
-    if !path_normalize(&path.to_str().unwrap()).starts_with(NOT_EXIST_DIR) {
-        trace!("source 路径跳出了当前目录，忽略这个文件: {}", s);
+    if let Err(e) = safe_relative(s) {
+        trace!("忽略这个文件: {}", e);
         return false;
     }
     true
 }
 
+/// 逐段规范化不可信的相对路径（比如 source map 里面的 sources）
+///
+/// `/` 和 `\` 都当作分隔符，拒绝绝对路径、windows 盘符、NUL 字符和跳出当前目录的 `..`
+pub fn safe_relative(s: &str) -> Result<PathBuf> {
+    if s.contains('\0') {
+        return Err(anyhow!("路径包含 NUL 字符: {:?}", s));
+    }
+    if s.starts_with('/') || s.starts_with('\\') {
+        return Err(anyhow!("不允许绝对路径: {}", s));
+    }
+    let mut parts: Vec<&str> = vec![];
+    for (i, part) in s.split(['/', '\\']).enumerate() {
+        if i == 0 && part.len() >= 2 && part.as_bytes()[1] == b':' {
+            return Err(anyhow!("不允许 windows 盘符路径: {}", s));
+        }
+        match part {
+            "" | "." => {}
+            ".." => {
+                if parts.pop().is_none() {
+                    return Err(anyhow!("source 路径跳出了当前目录: {}", s));
+                }
+            }
+            _ => parts.push(part),
+        }
+    }
+    if parts.is_empty() {
+        return Err(anyhow!("路径为空: {:?}", s));
+    }
+    Ok(parts.iter().collect())
+}
+
+/// 把不可信的相对路径拼到 `base` 下面，路径上已经存在的符号链接不能指向 `base` 以外
+pub fn safe_join(base: &Path, s: &str) -> Result<PathBuf> {
+    let rel = safe_relative(s)?;
+    let mut p = base.to_path_buf();
+    let mut real_base = None;
+    for c in rel.components() {
+        p.push(c);
+        let Ok(meta) = std::fs::symlink_metadata(&p) else {
+            // 后面的路径还不存在，不会再有符号链接
+            break;
+        };
+        if meta.file_type().is_symlink() {
+            if real_base.is_none() {
+                real_base = Some(base.canonicalize()?);
+            }
+            let target = p.canonicalize().unwrap_or_default();
+            if !target.starts_with(real_base.as_ref().unwrap()) {
+                return Err(anyhow!(
+                    "符号链接指向了输出目录以外: {} -> {}",
+                    p.to_string_lossy(),
+                    target.to_string_lossy()
+                ));
+            }
+        }
+    }
+    Ok(base.join(rel))
+}
+
+/// 没有 ignoreList 的 source map 只能根据路径猜测是不是第三方代码
+pub fn is_vendor_source_path(s: &str) -> bool {
+    s.contains("node_modules")
+}
+
 pub fn url_key(u: &str) -> String {
     // 定义一个正则表达式
     let re = Regex::new(r"\W+").unwrap();
@@ -77,21 +137,10 @@ pub fn hash(s: &str) -> String {
 #[instrument]
 pub async fn get_uri_resource(uri: &str) -> Result<String> {
     let _timer = Timer::new(&format!("获取资源{}", uri));
-    let u = Url::parse(uri)?;
-    match u.scheme() {
-        "file" => {
-            let p = PathBuf::from(u.path());
-            Ok(fs::read_to_string(p).await?)
-        }
-        "https" | "http" => {
-            let resp = reqwest::get(uri).await?;
-            if !resp.status().is_success() {
-                return Err(anyhow!("请求远程资源失败: [http={}]{}", resp.status(), &uri));
-            }
-            Ok(resp.text().await?)
-        }
-        _ => Err(anyhow!("unsupported scheme: {}", u.scheme())),
-    }
+    Resolver::default()
+        .resolve(uri)
+        .await?
+        .ok_or(anyhow!("内部模块没有源码: {}", uri))
 }
 #[cfg(test)]
 mod test {
@@ -113,6 +162,50 @@ mod test {
             glob_abs(&format!("{}/tests/base/**/*.json", cwd.to_str().unwrap())).unwrap()
         );
     }
+    #[test]
+    fn test_safe_relative() {
+        assert_eq!(
+            safe_relative("src/./a/../b.js").unwrap(),
+            PathBuf::from("src/b.js")
+        );
+        assert_eq!(
+            safe_relative("src\\b.js").unwrap(),
+            PathBuf::from("src/b.js")
+        );
+        assert!(safe_relative("../a.js").is_err());
+        assert!(safe_relative("src/../../a.js").is_err());
+        assert!(safe_relative("/etc/passwd").is_err());
+        assert!(safe_relative("\\\\server\\share").is_err());
+        assert!(safe_relative("C:\\Windows\\a.js").is_err());
+        assert!(safe_relative("c:a.js").is_err());
+        assert!(safe_relative("a\0.js").is_err());
+        assert!(safe_relative("./").is_err());
+        assert!(is_legal_source_path("src/main.js"));
+        assert!(!is_legal_source_path("../../main.js"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_safe_join_symlink() -> Result<()> {
+        let root = env::temp_dir().join(format!("v8-to-istanbul-join-{}", std::process::id()));
+        let base = root.join("out");
+        let evil = root.join("out-evil");
+        std::fs::create_dir_all(base.join("inner"))?;
+        std::fs::create_dir_all(&evil)?;
+        std::os::unix::fs::symlink(&evil, base.join("link"))?;
+        std::os::unix::fs::symlink(base.join("inner"), base.join("inner-link"))?;
+
+        assert_eq!(safe_join(&base, "a/b.js")?, base.join("a/b.js"));
+        assert_eq!(
+            safe_join(&base, "inner-link/b.js")?,
+            base.join("inner-link/b.js")
+        );
+        assert!(safe_join(&base, "link/b.js").is_err());
+        assert!(safe_join(&base, "../out-evil/b.js").is_err());
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
     #[test]
     fn test_url() {
         dbg!(Url::parse("file://user/local/abc"));
@@ -1,6 +1,9 @@
 mod cmd;
+mod css;
 mod format;
 mod fputil;
+mod pragma;
+mod resolver;
 mod statement;
 mod timer;
 mod translate;
@@ -8,6 +11,14 @@ mod traverse;
 
 use crate::cmd::convert;
 use crate::cmd::convert::ConvertArgs;
+use crate::cmd::inspect;
+use crate::cmd::inspect::InspectArgs;
+use crate::cmd::merge_v8;
+use crate::cmd::merge_v8::MergeV8Args;
+use crate::cmd::overlay;
+use crate::cmd::overlay::OverlayArgs;
+use crate::cmd::serve;
+use crate::cmd::serve::ServeArgs;
 use crate::timer::Timer;
 use anyhow::Result;
 use clap::{Parser, Subcommand};
@@ -23,7 +34,15 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Adds files to myapp
-    Convert(ConvertArgs),
+    Convert(Box<ConvertArgs>),
+    /// 合并多个 v8 覆盖率文件里面相同脚本的原始区间
+    MergeV8(MergeV8Args),
+    /// 在本地启动 http 服务查看报告，覆盖率文件更新之后自动刷新
+    Serve(ServeArgs),
+    /// 查看源码某个位置对应的 MappingItem、v8 区间和生成代码
+    Inspect(InspectArgs),
+    /// 生成代码和源码并排显示的 html，按 v8 区间的计数着色
+    Overlay(OverlayArgs),
 }
 
 #[tokio::main]
@@ -38,6 +57,10 @@ async fn main() -> Result<()> {
     // matches just as you would the top level cmd
     match &cli.command {
         Commands::Convert(args) => convert::exec(args).await?,
+        Commands::MergeV8(args) => merge_v8::exec(args).await?,
+        Commands::Serve(args) => serve::exec(args).await?,
+        Commands::Inspect(args) => inspect::exec(args).await?,
+        Commands::Overlay(args) => overlay::exec(args).await?,
     }
     Ok(())
 }
@@ -0,0 +1,275 @@
+use crate::traverse::{CodeSpan, ParsedCode};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use tracing::debug;
+
+/// 源码中被 `istanbul ignore` / `c8 ignore` 注释排除的区域
+///
+/// 位置都是 (line, column)，从 0 开始，start 包含，end 不包含
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct IgnoreRegions {
+    pub whole_file: bool,
+    pub ranges: Vec<((u32, u32), (u32, u32))>,
+}
+
+impl IgnoreRegions {
+    pub fn is_empty(&self) -> bool {
+        !self.whole_file && self.ranges.is_empty()
+    }
+
+    /// `[start, end)` 整段都在某个排除区域内
+    pub fn is_ignored(&self, start: (u32, u32), end: (u32, u32)) -> bool {
+        self.whole_file || self.ranges.iter().any(|(s, e)| *s <= start && end <= *e)
+    }
+
+    pub fn is_span_ignored(&self, span: &CodeSpan) -> bool {
+        self.is_ignored(
+            (span.start_line, span.start_column),
+            (span.end_line, span.end_column),
+        )
+    }
+
+    fn push_span(&mut self, span: &CodeSpan) {
+        self.ranges.push((
+            (span.start_line, span.start_column),
+            (span.end_line, span.end_column),
+        ));
+    }
+
+    fn push_lines(&mut self, first: u32, last: u32) {
+        self.ranges.push(((first, 0), (last, u32::MAX)));
+    }
+}
+
+fn pragma_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(concat!(
+            r"(/\*|//)\s*(?:",
+            r"istanbul\s+ignore\s+(?P<istanbul>next|if|else|file)\b",
+            r"|c8\s+ignore\s+(?:(?P<c8>start|stop)\b|(?P<c8_next>next)\b(?:\s+(?P<count>\d+))?)",
+            r")"
+        ))
+        .unwrap()
+    })
+}
+
+struct Pragma<'a> {
+    tool: &'a str,
+    kind: &'a str,
+    count: Option<u32>,
+    // 注释开始和结束位置的 (line, column, offset)
+    start: (u32, u32, u32),
+    end: (u32, u32, u32),
+}
+
+/// 根据注释计算需要排除的区域，和 nyc / c8 的语义保持一致
+///
+/// - `istanbul ignore next`：排除注释之后的下一个语句或函数
+/// - `istanbul ignore if` / `istanbul ignore else`：排除下一个 if 语句的 if / else 分支
+/// - `c8 ignore next [N]`：排除注释之后的 N 行，默认 1 行
+/// - `c8 ignore start` ... `c8 ignore stop`：排除两个注释之间的代码
+/// - `istanbul ignore file`：排除整个文件
+///
+/// 两种注释的关键字不能混用，比如 `istanbul ignore start` 和 `c8 ignore file` 都会被忽略
+///
+/// 没有语法树（源码解析失败）的时候，`istanbul ignore next` 退化成排除下一行
+pub fn parse_ignores(code: &str, parsed: Option<&ParsedCode>) -> IgnoreRegions {
+    let mut r = IgnoreRegions::default();
+    if !code.contains("ignore") {
+        return r;
+    }
+    let mut line_starts = vec![0];
+    for (i, c) in code.char_indices() {
+        if c == '\n' {
+            line_starts.push(i + 1);
+        }
+    }
+    let position = |byte: usize| -> (u32, u32, u32) {
+        let line = line_starts.partition_point(|&s| s <= byte) - 1;
+        let column = code[line_starts[line]..byte].chars().count() as u32;
+        let offset = code[..line_starts[line]].chars().count() as u32 + column;
+        (line as u32, column, offset)
+    };
+
+    let mut open_start: Option<(u32, u32)> = None;
+    for cap in pragma_regex().captures_iter(code) {
+        let m = cap.get(0).unwrap();
+        let end = if &cap[1] == "/*" {
+            code[m.end()..]
+                .find("*/")
+                .map(|i| m.end() + i + 2)
+                .unwrap_or(code.len())
+        } else {
+            code[m.end()..]
+                .find('\n')
+                .map(|i| m.end() + i)
+                .unwrap_or(code.len())
+        };
+        let (tool, kind) = match (cap.name("istanbul"), cap.name("c8")) {
+            (Some(k), _) => ("istanbul", k.as_str()),
+            (_, Some(k)) => ("c8", k.as_str()),
+            _ => ("c8", "next"),
+        };
+        let p = Pragma {
+            tool,
+            kind,
+            count: cap.name("count").and_then(|c| c.as_str().parse().ok()),
+            start: position(m.start()),
+            end: position(end),
+        };
+        debug!(
+            tool = p.tool,
+            kind = p.kind,
+            line = p.start.0,
+            "ignore 注释"
+        );
+        match (p.tool, p.kind) {
+            (_, "file") => r.whole_file = true,
+            (_, "start") => open_start = Some((p.start.0, p.start.1)),
+            (_, "stop") => {
+                if let Some(s) = open_start.take() {
+                    r.ranges.push((s, (p.end.0, p.end.1)));
+                }
+            }
+            ("c8", "next") => {
+                let n = p.count.unwrap_or(1).max(1);
+                r.push_lines(p.end.0 + 1, p.end.0 + n);
+            }
+            (_, "next") => match parsed.and_then(|parsed| next_node(parsed, p.end.2)) {
+                Some(span) => r.push_span(&span),
+                None => r.push_lines(p.end.0 + 1, p.end.0 + 1),
+            },
+            (_, kind) => {
+                let next_if = parsed.and_then(|parsed| {
+                    parsed
+                        .ifs
+                        .iter()
+                        .filter(|i| i.loc.start >= p.end.2)
+                        .min_by_key(|i| i.loc.start)
+                });
+                match (kind, next_if) {
+                    ("if", Some(i)) => r.push_span(&i.cons),
+                    ("else", Some(i)) => {
+                        if let Some(alt) = &i.alt {
+                            r.push_span(alt)
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    // 只有 start 没有 stop，一直排除到文件结束
+    if let Some(s) = open_start {
+        r.ranges.push((s, (u32::MAX, u32::MAX)));
+    }
+    r
+}
+
+/// 注释之后紧跟着的最外层节点
+fn next_node(parsed: &ParsedCode, offset: u32) -> Option<CodeSpan> {
+    parsed
+        .statements
+        .iter()
+        .chain(parsed.functions.iter().map(|f| &f.loc))
+        .chain(parsed.ifs.iter().map(|i| &i.loc))
+        .filter(|s| s.start >= offset)
+        .min_by(|a, b| a.start.cmp(&b.start).then(b.end.cmp(&a.end)))
+        .cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::traverse::parse_code;
+
+    fn ignores(code: &str) -> IgnoreRegions {
+        let parsed = parse_code(code, "a.js").unwrap();
+        parse_ignores(code, Some(&parsed))
+    }
+
+    #[test]
+    fn test_istanbul_ignore_next() {
+        let code = r#"const a = 1;
+/* istanbul ignore next */
+function foo() {
+  return 1;
+}
+foo();
+"#;
+        let r = ignores(code);
+        assert!(r.is_ignored((2, 0), (4, 1)));
+        assert!(r.is_ignored((3, 2), (3, 11)));
+        assert!(!r.is_ignored((0, 0), (0, 12)));
+        assert!(!r.is_ignored((5, 0), (5, 6)));
+    }
+
+    #[test]
+    fn test_istanbul_ignore_if_else() {
+        let code = r#"const a = Math.random();
+/* istanbul ignore else */
+if (a > 0.5) {
+  console.log(1);
+} else {
+  console.log(2);
+}
+// istanbul ignore if
+if (a > 0.1) {
+  console.log(3);
+}
+"#;
+        let r = ignores(code);
+        assert!(!r.is_ignored((3, 2), (3, 17)));
+        assert!(r.is_ignored((5, 2), (5, 17)));
+        assert!(r.is_ignored((9, 2), (9, 17)));
+    }
+
+    #[test]
+    fn test_c8_ignore() {
+        let code = r#"const a = 1;
+/* c8 ignore start */
+const b = 2;
+const c = 3;
+/* c8 ignore stop */
+const d = 4;
+// c8 ignore next 2
+const e = 5;
+const f = 6;
+const g = 7;
+"#;
+        let r = ignores(code);
+        assert!(!r.is_ignored((0, 0), (0, 12)));
+        assert!(r.is_ignored((2, 0), (2, 12)));
+        assert!(r.is_ignored((3, 0), (3, 12)));
+        assert!(!r.is_ignored((5, 0), (5, 12)));
+        assert!(r.is_ignored((7, 0), (7, 12)));
+        assert!(r.is_ignored((8, 0), (8, 12)));
+        assert!(!r.is_ignored((9, 0), (9, 12)));
+    }
+
+    #[test]
+    fn test_ignore_file() {
+        let r = parse_ignores("/* istanbul ignore file */\nconst a = 1;", None);
+        assert!(r.whole_file);
+        assert!(r.is_ignored((1, 0), (1, 12)));
+        assert!(parse_ignores("const a = 1;", None).is_empty());
+    }
+
+    #[test]
+    fn test_unknown_pragma() {
+        for code in [
+            "/* c8 ignore file */\nconst a = 1;",
+            "/* istanbul ignore start */\nconst a = 1;",
+            "/* c8 ignore if */\nif (a) {}",
+            "/* istanbul ignore nextline */\nconst a = 1;",
+        ] {
+            assert!(parse_ignores(code, None).is_empty(), "{}", code);
+        }
+        // 只有 c8 ignore next 可以指定行数
+        let r = parse_ignores("// istanbul ignore next 3\na;\nb;\nc;", None);
+        assert!(r.is_ignored((1, 0), (1, 2)));
+        assert!(!r.is_ignored((2, 0), (2, 2)));
+    }
+}
@@ -0,0 +1,263 @@
+use crate::fputil::path_to_abs;
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use percent_encoding::percent_decode_str;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use tokio::fs;
+use tracing::{debug, instrument};
+use url::Url;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// 按 scheme 获取资源内容，返回 `None` 表示这个资源应该被跳过（比如 node 内部模块）
+pub trait SchemeHandler: Send + Sync {
+    fn schemes(&self) -> &[&'static str];
+    fn fetch<'a>(
+        &'a self,
+        uri: &'a Url,
+        base_dir: &'a Path,
+    ) -> BoxFuture<'a, Result<Option<String>>>;
+}
+
+pub struct Resolver {
+    base_dir: PathBuf,
+    handlers: Vec<Box<dyn SchemeHandler>>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        let base_dir = path_to_abs(".").unwrap_or_else(|_| PathBuf::from("."));
+        Resolver::new(base_dir)
+    }
+}
+
+impl Resolver {
+    /// 相对路径以 `base_dir` 为根目录解析
+    pub fn new<P: AsRef<Path>>(base_dir: P) -> Self {
+        let mut r = Resolver {
+            base_dir: base_dir.as_ref().to_path_buf(),
+            handlers: vec![],
+        };
+        r.register(Box::new(FileHandler));
+        r.register(Box::new(HttpHandler));
+        r.register(Box::new(DataHandler));
+        r.register(Box::new(NodeInternalHandler));
+        r
+    }
+
+    /// 后注册的 handler 优先级更高，可以覆盖默认的 scheme 处理方式
+    pub fn register(&mut self, handler: Box<dyn SchemeHandler>) {
+        self.handlers.insert(0, handler);
+    }
+
+    #[instrument(skip(self))]
+    pub async fn resolve(&self, uri: &str) -> Result<Option<String>> {
+        let u = match parse_uri(uri) {
+            Some(u) => u,
+            None => {
+                let p = self.base_dir.join(uri);
+                debug!(path = p.to_str(), "按本地相对路径读取");
+                return Ok(Some(fs::read_to_string(&p).await.map_err(|e| {
+                    anyhow!("读取本地文件失败: {}, {}", p.to_string_lossy(), e)
+                })?));
+            }
+        };
+        match self
+            .handlers
+            .iter()
+            .find(|h| h.schemes().contains(&u.scheme()))
+        {
+            Some(h) => h.fetch(&u, &self.base_dir).await,
+            None => Err(anyhow!("unsupported scheme: {}", u.scheme())),
+        }
+    }
+}
+
+/// 不是合法 URL 的字符串，或者 windows 盘符路径（`C:\a.js` 会被解析成 scheme 为 `c` 的 URL）
+/// 都当作本地路径处理
+fn parse_uri(uri: &str) -> Option<Url> {
+    match Url::parse(uri) {
+        Ok(u) if u.scheme().len() > 1 => Some(u),
+        _ => None,
+    }
+}
+
+/// node 内部模块，比如 `node:internal/modules/cjs/loader`，没有源码也不需要统计覆盖率
+pub fn is_internal(uri: &str) -> bool {
+    uri.starts_with("node:") || uri.starts_with("internal/")
+}
+
+/// `file://` URL 转换成本地路径
+///
+/// `file:///abs/path` 和 `file://localhost/abs/path` 是绝对路径；
+/// `file://user/local/abc` 这种带 host 的写法一般是把相对路径直接拼在了 `file://` 后面，
+/// 按 `base_dir` 下的相对路径 `user/local/abc` 处理
+pub fn file_url_to_path(u: &Url, base_dir: &Path) -> Result<PathBuf> {
+    match u.host_str() {
+        None | Some("") | Some("localhost") => u
+            .to_file_path()
+            .map_err(|_| anyhow!("无法转换成本地路径: {}", u)),
+        Some(host) => {
+            let path = percent_decode_str(u.path()).decode_utf8()?;
+            Ok(base_dir.join(format!("{}{}", host, path)))
+        }
+    }
+}
+
+struct FileHandler;
+impl SchemeHandler for FileHandler {
+    fn schemes(&self) -> &[&'static str] {
+        &["file"]
+    }
+    fn fetch<'a>(
+        &'a self,
+        uri: &'a Url,
+        base_dir: &'a Path,
+    ) -> BoxFuture<'a, Result<Option<String>>> {
+        Box::pin(async move {
+            let p = file_url_to_path(uri, base_dir)?;
+            Ok(Some(fs::read_to_string(&p).await.map_err(|e| {
+                anyhow!("读取本地文件失败: {}, {}", p.to_string_lossy(), e)
+            })?))
+        })
+    }
+}
+
+struct HttpHandler;
+impl SchemeHandler for HttpHandler {
+    fn schemes(&self) -> &[&'static str] {
+        &["http", "https"]
+    }
+    fn fetch<'a>(
+        &'a self,
+        uri: &'a Url,
+        _base_dir: &'a Path,
+    ) -> BoxFuture<'a, Result<Option<String>>> {
+        Box::pin(async move {
+            let resp = reqwest::get(uri.as_str()).await?;
+            if !resp.status().is_success() {
+                return Err(anyhow!("请求远程资源失败: [http={}]{}", resp.status(), uri));
+            }
+            Ok(Some(resp.text().await?))
+        })
+    }
+}
+
+struct DataHandler;
+impl SchemeHandler for DataHandler {
+    fn schemes(&self) -> &[&'static str] {
+        &["data"]
+    }
+    fn fetch<'a>(
+        &'a self,
+        uri: &'a Url,
+        _base_dir: &'a Path,
+    ) -> BoxFuture<'a, Result<Option<String>>> {
+        Box::pin(async move { decode_data_url(uri.as_str()).map(Some) })
+    }
+}
+
+/// 解析 `data:[<mediatype>][;base64],<data>`
+pub fn decode_data_url(uri: &str) -> Result<String> {
+    let body = uri
+        .strip_prefix("data:")
+        .ok_or(anyhow!("不是 data URL: {}", uri))?;
+    let (meta, data) = body
+        .split_once(',')
+        .ok_or(anyhow!("data URL 缺少 `,`: {}", uri))?;
+    let bytes = if meta.ends_with(";base64") {
+        let data = percent_decode_str(data).decode_utf8()?;
+        base64::engine::general_purpose::STANDARD
+            .decode(data.trim())
+            .map_err(|e| anyhow!("data URL base64 解码失败: {}", e))?
+    } else {
+        percent_decode_str(data).collect()
+    };
+    Ok(String::from_utf8(bytes)?)
+}
+
+struct NodeInternalHandler;
+impl SchemeHandler for NodeInternalHandler {
+    fn schemes(&self) -> &[&'static str] {
+        &["node"]
+    }
+    fn fetch<'a>(
+        &'a self,
+        uri: &'a Url,
+        _base_dir: &'a Path,
+    ) -> BoxFuture<'a, Result<Option<String>>> {
+        Box::pin(async move {
+            debug!("跳过 node 内部模块 {}", uri);
+            Ok(None)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve_local() -> Result<()> {
+        let r = Resolver::new(env!("CARGO_MANIFEST_DIR"));
+        let expect = include_str!("../tests/base/main.min.js");
+        assert_eq!(r.resolve("tests/base/main.min.js").await?.unwrap(), expect);
+        assert_eq!(
+            r.resolve("./tests/base/../base/main.min.js")
+                .await?
+                .unwrap(),
+            expect
+        );
+        let abs = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/base/main.min.js");
+        let u = Url::from_file_path(&abs).unwrap();
+        assert_eq!(r.resolve(u.as_str()).await?.unwrap(), expect);
+        assert_eq!(
+            r.resolve("file://tests/base/main.min.js").await?.unwrap(),
+            expect
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_url_to_path() -> Result<()> {
+        let base = Path::new("/base");
+        assert_eq!(
+            file_url_to_path(&Url::parse("file:///user/local/abc")?, base)?,
+            PathBuf::from("/user/local/abc")
+        );
+        assert_eq!(
+            file_url_to_path(&Url::parse("file://localhost/a%20b.js")?, base)?,
+            PathBuf::from("/a b.js")
+        );
+        assert_eq!(
+            file_url_to_path(&Url::parse("file://user/local/abc")?, base)?,
+            PathBuf::from("/base/user/local/abc")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_data_and_node() -> Result<()> {
+        let r = Resolver::default();
+        assert_eq!(
+            r.resolve("data:application/javascript;base64,Y29uc29sZS5sb2coMSk=")
+                .await?
+                .unwrap(),
+            "console.log(1)"
+        );
+        assert_eq!(
+            r.resolve("data:text/javascript,console.log(%22a%22)")
+                .await?
+                .unwrap(),
+            "console.log(\"a\")"
+        );
+        assert!(r
+            .resolve("node:internal/modules/cjs/loader")
+            .await?
+            .is_none());
+        assert!(r.resolve("ftp://example.com/a.js").await.is_err());
+        Ok(())
+    }
+}
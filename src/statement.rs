@@ -1,13 +1,21 @@
-use crate::format::istanbul::generate_source_code;
+pub mod cache;
+
+use crate::format::istanbul::{generate_source_code, SourceMaterializer};
 use crate::format::script_coverage::ScriptCoverage;
-use crate::format::MappingItem;
-use crate::fputil::{get_uri_resource, glob_abs};
+use crate::format::{path_normalize, MappingItem, NameItem};
+use crate::fputil::{get_uri_resource, glob_abs, safe_join};
+use crate::pragma::IgnoreRegions;
+use crate::statement::cache::{CacheKeyInput, StatementCache};
 use crate::timer::Timer;
-use crate::translate::source_map_link;
+use crate::translate::{
+    apply_ignores, collect_names, parse_original_sources, source_map_link, LinkOptions,
+};
+use crate::traverse::ParsedCode;
 use anyhow::anyhow;
 use anyhow::Result;
 use regex::Regex;
-use sourcemap::SourceMap;
+use serde::{Deserialize, Serialize};
+use sourcemap::{DecodedMap, SourceMap};
 use std::collections::HashMap;
 use std::fmt;
 use std::path::{Path, PathBuf};
@@ -15,11 +23,54 @@ use tokio::fs;
 use tracing::{debug, info, instrument, trace, warn};
 use url::Url;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Statement {
     pub source_url: String,
     pub code_dir: String,
     pub mapping: Vec<MappingItem>,
+    pub names: Vec<NameItem>,
+    pub parsed: HashMap<String, ParsedCode>,
+    pub ignores: HashMap<String, IgnoreRegions>,
+    /// 生成代码，只在内存里面保留，不写进缓存
+    #[serde(skip)]
+    pub code: String,
+    /// source map 里面的 sourcesContent，只在内存里面保留，不写进缓存
+    #[serde(skip)]
+    pub sources_content: HashMap<String, String>,
+}
+
+impl Statement {
+    /// mapping 涉及到的源码，inspect 和 overlay 用来显示源码
+    ///
+    /// 优先使用 sourcesContent，没有的话和 `parse_original_sources` 一样从 `code_dir` 下读取
+    pub async fn original_sources(&self) -> HashMap<String, String> {
+        let mut r = HashMap::new();
+        for m in self.mapping.iter() {
+            if r.contains_key(&m.source) {
+                continue;
+            }
+            let content = match self.sources_content.get(&m.source) {
+                Some(c) => c.clone(),
+                None => match safe_join(Path::new(&self.code_dir), &m.source) {
+                    Ok(p) => match fs::read_to_string(p).await {
+                        Ok(c) => c,
+                        Err(_) => continue,
+                    },
+                    Err(_) => continue,
+                },
+            };
+            r.insert(m.source.clone(), content);
+        }
+        r
+    }
+}
+
+/// source map 里面的 sourcesContent，key 是 relocate 之后的 source
+fn embedded_sources(sm: &SourceMap) -> HashMap<String, String> {
+    sm.sources()
+        .enumerate()
+        .filter_map(|(i, s)| Some((s.to_string(), sm.get_source_contents(i as u32)?.to_string())))
+        .collect()
 }
 
 #[instrument]
@@ -28,17 +79,39 @@ pub async fn build_statements_from_local(
     url_base: &Option<String>,
     project_dir: &str,
     source_relocate: &Option<(Regex, String)>,
+    link_options: &LinkOptions,
+    materialize_sources: bool,
+    cache: Option<&StatementCache>,
 ) -> Result<HashMap<String, Statement>> {
     let _timer = Timer::new("本地构造Statements");
     let mut cache_data = HashMap::new();
     let all_source_map_files = glob_abs(source_map_pattern)?;
     info!("待处理的SourceMap文件列表 {:?}", &all_source_map_files);
+    let mut materializer =
+        materialize_sources.then(|| SourceMaterializer::new(project_dir, link_options));
     for p in all_source_map_files {
-        let (script_name, statement) =
-            handle_sourcemap_file(p.to_str().unwrap(), url_base, project_dir, source_relocate)
-                .await?;
+        if p.to_string_lossy().ends_with(".css.map") {
+            // css 的 source map 由 css 模块单独处理
+            continue;
+        }
+        let (script_name, statement) = handle_sourcemap_file(
+            p.to_str().unwrap(),
+            url_base,
+            project_dir,
+            source_relocate,
+            link_options,
+            materializer.as_mut(),
+            cache,
+        )
+        .await?;
         cache_data.insert(script_name, statement);
     }
+    if let Some(m) = materializer.filter(|m| m.conflicts > 0) {
+        warn!(
+            "{} 个源码文件在不同的 source map 里面内容不一致，覆盖率可能不准确",
+            m.conflicts
+        );
+    }
 
     Ok(cache_data)
 }
@@ -48,32 +121,74 @@ async fn handle_sourcemap_file(
     uri_base: &Option<String>,
     project_dir: &str,
     source_relocate: &Option<(Regex, String)>,
+    link_options: &LinkOptions,
+    materializer: Option<&mut SourceMaterializer>,
+    cache: Option<&StatementCache>,
 ) -> Result<(String, Statement)> {
     let _timer = Timer::new("处理SourceMap文件");
     trace!("处理SourceMap文件");
-    let sm = source_map_from_file(&p, source_relocate).await?;
-    let script_uri = if let Some(ub) = uri_base {
-        format!("{}{}", ub, sm.get_file().unwrap_or_default())
-    } else {
-        sm.get_file().unwrap_or_default().to_string()
-    };
+    let map_content = fs::read_to_string(p)
+        .await
+        .map_err(|err| anyhow!("读取SourceMap失败: {}, {}", err, p))?;
+    let mut sm = decode_source_map(map_content.as_bytes(), p).await?;
+    relocate_sources(&mut sm, source_relocate);
+    if let Some(m) = materializer {
+        let n = m.add(p, &sm).await?;
+        debug!(count = n, "写入 sourcesContent 源码");
+    }
+    let script_uri = script_uri(&sm, uri_base);
 
     debug!(script_uri = &script_uri, "下载SourceMap对应的JS文件");
     let source_content = get_uri_resource(&script_uri).await?;
+    let script_name = crate::format::script_coverage::url_filename(&script_uri);
+
+    let key = match cache {
+        Some(_) => Some(
+            StatementCache::key(&CacheKeyInput {
+                map_content: &map_content,
+                sm: &sm,
+                script_uri: &script_uri,
+                script_content: &source_content,
+                project_dir,
+                source_relocate,
+                link_options,
+            })
+            .await,
+        ),
+        None => None,
+    };
+    if let (Some(cache), Some(key)) = (cache, &key) {
+        if let Some(mut statement) = cache.load(key).await {
+            debug!(key = key, "使用缓存的中间数据");
+            statement.sources_content = embedded_sources(&sm);
+            statement.code = source_content;
+            return Ok((script_name, statement));
+        }
+    }
 
     debug!("生成map中间文件");
-    let vm = source_map_link(&source_content, &sm)
+    let vm = source_map_link(&source_content, &sm, link_options)
         .await
         .map_err(|e| anyhow!("生成覆盖率中间数据失败: {}", e))?;
-    let script_name = crate::format::script_coverage::url_filename(&script_uri);
-    Ok((
-        script_name,
-        Statement {
-            source_url: script_uri,
-            code_dir: project_dir.to_string(),
-            mapping: vm,
-        },
-    ))
+    let names = collect_names(&source_content, &sm, link_options);
+    let (parsed, ignores) = parse_original_sources(&sm, link_options, project_dir);
+    let vm = apply_ignores(vm, &ignores);
+    let statement = Statement {
+        source_url: script_uri,
+        code_dir: project_dir.to_string(),
+        mapping: vm,
+        names,
+        parsed,
+        ignores,
+        code: source_content,
+        sources_content: embedded_sources(&sm),
+    };
+    if let (Some(cache), Some(key)) = (cache, &key) {
+        if let Err(e) = cache.store(key, &statement).await {
+            warn!("写入缓存失败: {}", e);
+        }
+    }
+    Ok((script_name, statement))
 }
 
 pub async fn build_statements(
@@ -110,7 +225,7 @@ pub async fn build_statements(
 }
 
 #[instrument]
-async fn source_map_from_file<P: AsRef<Path> + fmt::Debug>(
+pub async fn source_map_from_file<P: AsRef<Path> + fmt::Debug>(
     p: P,
     source_relocate: &Option<(Regex, String)>,
 ) -> Result<SourceMap> {
@@ -122,21 +237,18 @@ async fn source_map_from_file<P: AsRef<Path> + fmt::Debug>(
         )
     })?;
     trace!("解码 source map");
-    let mut sm =
-        SourceMap::from_slice(s.as_bytes()).map_err(|e| anyhow!("sourcemap 解析失败: {}", e))?;
-
-    // source 字段对应的文件路径需要重新定位一下
-    if let Some((re, replace)) = source_relocate {
-        let n = sm.get_source_count();
-        for i in 0..n {
-            if let Some(s) = sm.get_source(i) {
-                let s = re.replace(s, replace.as_str()).to_string();
-                sm.set_source(i, s.as_str())
-            }
-        }
-    }
+    let mut sm = decode_source_map(s.as_bytes(), &p.as_ref().to_string_lossy()).await?;
+    relocate_sources(&mut sm, source_relocate);
     Ok(sm)
 }
+/// source map 对应的脚本地址，`url_base` 用来补全 source map 里面的 file
+fn script_uri(sm: &SourceMap, url_base: &Option<String>) -> String {
+    match url_base {
+        Some(ub) => format!("{}{}", ub, sm.get_file().unwrap_or_default()),
+        None => sm.get_file().unwrap_or_default().to_string(),
+    }
+}
+
 #[instrument()]
 async fn source_map_from_url(
     u: &str,
@@ -144,19 +256,8 @@ async fn source_map_from_url(
 ) -> Result<SourceMap> {
     let s = get_uri_resource(u).await?;
     trace!("解码 source map");
-    let mut sm =
-        SourceMap::from_slice(s.as_bytes()).map_err(|e| anyhow!("sourcemap 解析失败: {}", e))?;
-
-    // source 字段对应的文件路径需要重新定位一下
-    if let Some((re, replace)) = source_relocate {
-        let n = sm.get_source_count();
-        for i in 0..n {
-            if let Some(s) = sm.get_source(i) {
-                let s = re.replace(s, replace.as_str()).to_string();
-                sm.set_source(i, s.as_str())
-            }
-        }
-    }
+    let mut sm = decode_source_map(s.as_bytes(), u).await?;
+    relocate_sources(&mut sm, &source_relocate);
     Ok(sm)
 }
 
@@ -179,27 +280,22 @@ async fn gen_cache_data<'a>(
         None => None,
     };
     // source map 可以从网络下载，或者本地查找
-    let smb = match sm_path {
-        Some(s) => fs::read_to_string(&s)
-            .await
-            .map_err(|e| anyhow!("读取 sourcemap 路径错误: {}", e))?,
-        None => reqwest::get(&format!("{}.map", &url)).await?.text().await?,
+    let (smb, sm_uri) = match sm_path {
+        Some(s) => (
+            fs::read_to_string(&s)
+                .await
+                .map_err(|e| anyhow!("读取 sourcemap 路径错误: {}", e))?,
+            s,
+        ),
+        None => {
+            let u = format!("{}.map", &url);
+            (reqwest::get(&u).await?.text().await?, u)
+        }
     };
 
     trace!("解码 source map");
-    let mut sm =
-        SourceMap::from_slice(smb.as_bytes()).map_err(|e| anyhow!("sourcemap 解析失败: {}", e))?;
-
-    // source 字段对应的文件路径需要重新定位一下
-    if let Some((re, replace)) = source_relocate {
-        let n = sm.get_source_count();
-        for i in 0..n {
-            if let Some(s) = sm.get_source(i) {
-                let s = re.replace(s, replace.as_str()).to_string();
-                sm.set_source(i, s.as_str())
-            }
-        }
-    }
+    let mut sm = decode_source_map(smb.as_bytes(), &sm_uri).await?;
+    relocate_sources(&mut sm, &source_relocate);
     // 生成源码目录
     let base_dir = output_dir.to_string();
     if !use_local {
@@ -208,17 +304,137 @@ async fn gen_cache_data<'a>(
     }
     // 生成中间文件
     trace!("生成中间文件");
-    let vm = source_map_link(&source, &sm)
+    let vm = source_map_link(&source, &sm, &LinkOptions::default())
         .await
         .map_err(|e| anyhow!("生成覆盖率中间数据失败, {}", e))?;
+    let names = collect_names(source, &sm, &LinkOptions::default());
+    let (parsed, ignores) = parse_original_sources(&sm, &LinkOptions::default(), &base_dir);
+    let vm = apply_ignores(vm, &ignores);
     trace!("源码预处理完成");
     Ok(Statement {
         source_url: url.to_string(),
         code_dir: base_dir,
         mapping: vm,
+        names,
+        parsed,
+        ignores,
+        code: source.to_string(),
+        sources_content: embedded_sources(&sm),
     })
 }
 
+/// 解码 source map，index map（带 sections）会被展开成普通的 source map
+///
+/// section 只有 `url` 没有 `map` 的时候，相对 `base` 获取对应的 source map
+pub async fn decode_source_map(s: &[u8], base: &str) -> Result<SourceMap> {
+    let mut dm = sourcemap::decode_slice(s).map_err(|e| anyhow!("sourcemap 解析失败: {}", e))?;
+    if let Ok(raw) = serde_json::from_slice::<serde_json::Value>(s) {
+        apply_google_ignore_list(&mut dm, &raw);
+    }
+    match dm {
+        DecodedMap::Regular(sm) => Ok(sm),
+        DecodedMap::Hermes(smh) => Ok((*smh).clone()),
+        DecodedMap::Index(mut smi) => {
+            trace!("展开 index source map");
+            for i in 0..smi.get_section_count() {
+                let section = match smi.get_section_mut(i) {
+                    Some(section) => section,
+                    None => continue,
+                };
+                if section.get_sourcemap().is_some() {
+                    continue;
+                }
+                if let Some(u) = section.get_url() {
+                    let u = join_uri(base, u);
+                    debug!(url = &u, "获取 section 对应的 source map");
+                    let content = get_uri_resource(&u).await?;
+                    let mut dm = sourcemap::decode_slice(content.as_bytes())
+                        .map_err(|e| anyhow!("section sourcemap 解析失败: {} {}", &u, e))?;
+                    if let Ok(raw) = serde_json::from_str::<serde_json::Value>(&content) {
+                        apply_google_ignore_list(&mut dm, &raw);
+                    }
+                    section.set_sourcemap(Some(dm));
+                }
+            }
+            smi.flatten()
+                .map_err(|e| anyhow!("展开 index source map 失败: {}", e))
+        }
+    }
+}
+
+/// sourcemap 库只认 `ignoreList`，旧版本 chrome 定义的 `x_google_ignoreList` 需要自己补上
+fn apply_google_ignore_list(dm: &mut DecodedMap, raw: &serde_json::Value) {
+    fn read(raw: &serde_json::Value) -> Vec<u32> {
+        raw.get("x_google_ignoreList")
+            .and_then(|v| v.as_array())
+            .map(|v| {
+                v.iter()
+                    .filter_map(|x| x.as_u64())
+                    .map(|x| x as u32)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+    match dm {
+        DecodedMap::Regular(sm) => {
+            for idx in read(raw) {
+                sm.add_to_ignore_list(idx);
+            }
+        }
+        DecodedMap::Index(smi) => {
+            let sections = match raw.get("sections").and_then(|v| v.as_array()) {
+                Some(v) => v,
+                None => return,
+            };
+            for (i, raw_section) in sections.iter().enumerate() {
+                let ignored = match raw_section.get("map") {
+                    Some(m) => read(m),
+                    None => continue,
+                };
+                if ignored.is_empty() {
+                    continue;
+                }
+                if let Some(section) = smi.get_section_mut(i as u32) {
+                    if let Some(DecodedMap::Regular(mut sm)) = section.get_sourcemap().cloned() {
+                        for idx in ignored {
+                            sm.add_to_ignore_list(idx);
+                        }
+                        section.set_sourcemap(Some(DecodedMap::Regular(sm)));
+                    }
+                }
+            }
+        }
+        DecodedMap::Hermes(_) => {}
+    }
+}
+
+/// 相对 `base`（文件路径或者 URL）解析 `u`
+pub fn join_uri(base: &str, u: &str) -> String {
+    if Url::parse(u).is_ok_and(|x| x.scheme().len() > 1) {
+        return u.to_string();
+    }
+    match Url::parse(base) {
+        Ok(b) if b.scheme().len() > 1 => b.join(u).map(|x| x.to_string()).unwrap_or(u.to_string()),
+        _ => Path::new(base)
+            .parent()
+            .map(|p| path_normalize(&p.join(u).to_string_lossy()))
+            .unwrap_or(u.to_string()),
+    }
+}
+
+/// source 字段对应的文件路径需要重新定位一下
+pub fn relocate_sources(sm: &mut SourceMap, source_relocate: &Option<(Regex, String)>) {
+    if let Some((re, replace)) = source_relocate {
+        let n = sm.get_source_count();
+        for i in 0..n {
+            if let Some(s) = sm.get_source(i) {
+                let s = re.replace(s, replace.as_str()).to_string();
+                sm.set_source(i, s.as_str())
+            }
+        }
+    }
+}
+
 fn url_key(u: &str) -> String {
     // 定义一个正则表达式
     let re = Regex::new(r"\W+").unwrap();
@@ -257,3 +473,173 @@ fn get_js_filename(u: &str, source_map_base: &str) -> Option<String> {
     }
     None
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_json_diff::assert_json_eq;
+
+    #[tokio::test]
+    async fn test_index_source_map() -> Result<()> {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let source = include_str!("../tests/base/main.min.js");
+        let expect: Vec<MappingItem> =
+            serde_json::from_str(include_str!("../tests/base/source_map_link.json"))?;
+
+        let sm =
+            source_map_from_file(format!("{}/tests/index/main.min.js.map", dir), &None).await?;
+        assert_eq!(sm.get_file(), Some("main.min.js"));
+        assert_json_eq!(
+            source_map_link(source, &sm, &LinkOptions::default()).await?,
+            &expect
+        );
+
+        let sm =
+            source_map_from_file(format!("{}/tests/index/main.url.min.js.map", dir), &None).await?;
+        assert_json_eq!(
+            source_map_link(source, &sm, &LinkOptions::default()).await?,
+            &expect
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_google_ignore_list() -> Result<()> {
+        let mut raw: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/base/main.min.js.map"))?;
+        raw["x_google_ignoreList"] = serde_json::json!([0]);
+        let sm = decode_source_map(raw.to_string().as_bytes(), "main.min.js.map").await?;
+        assert_eq!(sm.ignore_list().copied().collect::<Vec<_>>(), vec![0]);
+
+        let mut raw: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/index/main.min.js.map"))?;
+        raw["sections"][0]["map"]["x_google_ignoreList"] = serde_json::json!([0]);
+        let sm = decode_source_map(raw.to_string().as_bytes(), "main.min.js.map").await?;
+        assert_eq!(sm.ignore_list().copied().collect::<Vec<_>>(), vec![0]);
+
+        let source = include_str!("../tests/base/main.min.js");
+        let r = source_map_link(source, &sm, &LinkOptions::default()).await?;
+        assert!(r.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_source_map_offset() -> Result<()> {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let sm =
+            source_map_from_file(format!("{}/tests/index/multi.min.js.map", dir), &None).await?;
+        let tokens: Vec<_> = sm
+            .tokens()
+            .map(|t| {
+                (
+                    t.get_dst_line(),
+                    t.get_dst_col(),
+                    t.get_source().unwrap_or_default().to_string(),
+                    t.get_src_line(),
+                    t.get_src_col(),
+                )
+            })
+            .collect();
+        let token = |dst_line, dst_col, source: &str, src_line, src_col| {
+            (dst_line, dst_col, source.to_string(), src_line, src_col)
+        };
+        // 第二个 section 的列偏移只作用在它的第一行
+        assert_eq!(
+            tokens,
+            vec![
+                token(0, 0, "src/a.js", 0, 0),
+                token(0, 4, "src/a.js", 0, 4),
+                token(1, 4, "src/b.js", 0, 0),
+                token(1, 6, "src/b.js", 0, 1),
+                token(2, 0, "src/b.js", 1, 1),
+            ]
+        );
+        // 通过 url 获取的 section 也要读取 x_google_ignoreList
+        let ignored: Vec<_> = sm.ignore_list().filter_map(|&i| sm.get_source(i)).collect();
+        assert_eq!(ignored, vec!["src/b.js"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_original_sources() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("v8-sources-content-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).await?;
+        let mut main: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/base/main.min.js.map"))?;
+        main["file"] = serde_json::json!("main.min.js");
+        fs::write(dir.join("main.min.js.map"), main.to_string()).await?;
+        let code = include_str!("../tests/base/main.min.js");
+        fs::write(dir.join("main.min.js"), code).await?;
+
+        let project_dir = dir.to_string_lossy().to_string();
+        let cache = StatementCache::new(dir.join("cache"));
+        // 第二次的结果来自缓存，生成代码和 sourcesContent 不在缓存里面，也要能拿到
+        for _ in 0..2 {
+            let r = build_statements_from_local(
+                &format!("{}/*.map", project_dir),
+                &Some(format!("{}/", project_dir)),
+                &project_dir,
+                &None,
+                &LinkOptions::default(),
+                false,
+                Some(&cache),
+            )
+            .await?;
+            let statement = &r["main.min.js"];
+            assert_eq!(statement.code, code);
+            let sources = statement.original_sources().await;
+            assert!(sources["src/main.js"].starts_with(";(() => {"));
+        }
+
+        // 没有 sourcesContent 的时候从 code_dir 读取，读不到的跳过
+        fs::write(dir.join("src/a.js"), "a;").await?;
+        let item = |source: &str| MappingItem {
+            source: source.to_string(),
+            generated_column: 0,
+            last_generated_column: 0,
+            original_line: 0,
+            original_column: 0,
+            last_original_line: 0,
+            last_original_column: 0,
+            count: 0,
+            idx: 0,
+            skip: false,
+        };
+        let statement = Statement {
+            source_url: "".to_string(),
+            code_dir: project_dir.clone(),
+            mapping: vec![item("src/a.js"), item("src/b.js"), item("src/c.js")],
+            names: vec![],
+            parsed: HashMap::new(),
+            ignores: HashMap::new(),
+            code: "".to_string(),
+            sources_content: HashMap::from([("src/c.js".to_string(), "c;".to_string())]),
+        };
+        assert_eq!(
+            statement.original_sources().await,
+            HashMap::from([
+                ("src/a.js".to_string(), "a;".to_string()),
+                ("src/c.js".to_string(), "c;".to_string())
+            ])
+        );
+
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_join_uri() {
+        assert_eq!(
+            join_uri("/a/b/main.js.map", "../c/x.map"),
+            "/a/c/x.map".to_string()
+        );
+        assert_eq!(
+            join_uri("https://cdn.com/a/main.js.map", "x.map"),
+            "https://cdn.com/a/x.map".to_string()
+        );
+        assert_eq!(
+            join_uri("/a/main.js.map", "https://cdn.com/x.map"),
+            "https://cdn.com/x.map".to_string()
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use crate::fputil::{hash, safe_join};
+use crate::statement::Statement;
+use crate::translate::LinkOptions;
+use anyhow::Result;
+use regex::Regex;
+use sha1::{Digest, Sha1};
+use sourcemap::SourceMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tracing::{debug, instrument, warn};
+
+/// source map 预处理结果的磁盘缓存
+///
+/// 只要 source map、脚本和参与计算的参数都没变，`source_map_link` 的结果就可以直接复用
+/// 缓存里面 Statement 的格式，结构变化的时候修改，旧的缓存自然失效
+const CACHE_FORMAT: &str = "2";
+
+/// 超过这个时间没有用过的缓存会被清理
+const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 3600);
+
+#[derive(Debug, Clone)]
+pub struct StatementCache {
+    dir: PathBuf,
+}
+
+/// 计算缓存 key 需要的输入，除了 source map 和脚本以外的参数也会影响预处理的结果
+pub struct CacheKeyInput<'a> {
+    pub map_content: &'a str,
+    pub sm: &'a SourceMap,
+    pub script_uri: &'a str,
+    pub script_content: &'a str,
+    pub project_dir: &'a str,
+    pub source_relocate: &'a Option<(Regex, String)>,
+    pub link_options: &'a LinkOptions,
+}
+
+impl StatementCache {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        StatementCache {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// 展开之后的 source map 也要算进 key 里面，index map 通过 `url` 引用的 section 变了缓存也会失效；
+    /// source map 里面没有 sourcesContent 的源码从 project_dir 读取，内容也要算进 key 里面
+    pub async fn key(input: &CacheKeyInput<'_>) -> String {
+        let mut flattened = vec![];
+        if let Err(e) = input.sm.to_writer(&mut flattened) {
+            debug!("序列化 source map 失败: {}", e);
+        }
+        let mut sources = vec![];
+        for (i, s) in input.sm.sources().enumerate() {
+            if input.sm.get_source_contents(i as u32).is_some() {
+                continue;
+            }
+            let content = match safe_join(Path::new(input.project_dir), s) {
+                Ok(p) => fs::read_to_string(p).await.ok(),
+                Err(_) => None,
+            };
+            sources.push((s, content.map(|c| hash(&c)).unwrap_or_default()));
+        }
+
+        let mut hasher = Sha1::new();
+        let mut field = |s: &[u8]| {
+            hasher.update(s.len().to_le_bytes());
+            hasher.update(s);
+        };
+        field(env!("CARGO_PKG_VERSION").as_bytes());
+        field(CACHE_FORMAT.as_bytes());
+        field(input.map_content.as_bytes());
+        field(&flattened);
+        field(input.script_uri.as_bytes());
+        field(input.script_content.as_bytes());
+        field(input.project_dir.as_bytes());
+        match input.source_relocate {
+            Some((re, replace)) => {
+                field(re.as_str().as_bytes());
+                field(replace.as_bytes());
+            }
+            None => field(b""),
+        }
+        field(format!("{:?}", input.link_options).as_bytes());
+        for (s, h) in sources {
+            field(s.as_bytes());
+            field(h.as_bytes());
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    /// 缓存不存在或者格式不对的时候返回 None
+    ///
+    /// 命中的时候更新文件的修改时间，`prune` 按修改时间清理很久没用过的缓存
+    #[instrument(skip(self))]
+    pub async fn load(&self, key: &str) -> Option<Statement> {
+        let path = self.path(key);
+        let s = fs::read_to_string(&path).await.ok()?;
+        match serde_json::from_str(&s) {
+            Ok(statement) => {
+                if let Err(e) = touch(&path).await {
+                    debug!("更新缓存修改时间失败: {}", e);
+                }
+                Some(statement)
+            }
+            Err(e) => {
+                debug!("缓存格式不对，重新生成: {}", e);
+                None
+            }
+        }
+    }
+
+    /// 先写临时文件再改名，并发运行的时候不会读到写了一半的缓存
+    #[instrument(skip(self, statement))]
+    pub async fn store(&self, key: &str, statement: &Statement) -> Result<()> {
+        fs::create_dir_all(&self.dir).await?;
+        let tmp = self.dir.join(format!("{}.{}.tmp", key, std::process::id()));
+        fs::write(&tmp, serde_json::to_string(statement)?).await?;
+        if let Err(e) = fs::rename(&tmp, self.path(key)).await {
+            warn!("写入缓存失败: {}", e);
+            let _ = fs::remove_file(&tmp).await;
+        }
+        Ok(())
+    }
+
+    /// 删除超过 `MAX_AGE` 没有用过的缓存，返回删除的文件个数
+    ///
+    /// source map 或者脚本每变一次就会多一个缓存文件，不清理的话缓存目录会一直变大
+    #[instrument(skip(self))]
+    pub async fn prune(&self) -> Result<usize> {
+        let mut rd = match fs::read_dir(&self.dir).await {
+            Ok(rd) => rd,
+            Err(_) => return Ok(0),
+        };
+        let deadline = SystemTime::now() - MAX_AGE;
+        let mut n = 0;
+        while let Some(e) = rd.next_entry().await? {
+            let name = e.file_name().to_string_lossy().to_string();
+            if !name.ends_with(".json") && !name.ends_with(".tmp") {
+                continue;
+            }
+            let modified = e.metadata().await?.modified()?;
+            if modified < deadline {
+                fs::remove_file(e.path()).await?;
+                n += 1;
+            }
+        }
+        if n > 0 {
+            debug!(count = n, "清理过期的缓存");
+        }
+        Ok(n)
+    }
+}
+
+async fn touch(path: &Path) -> Result<()> {
+    let f = fs::OpenOptions::new().append(true).open(path).await?;
+    f.into_std().await.set_modified(SystemTime::now())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::statement::{build_statements_from_local, decode_source_map};
+    use std::collections::HashMap;
+
+    async fn build(
+        cache: &StatementCache,
+        project_dir: &str,
+    ) -> Result<HashMap<String, Statement>> {
+        build_statements_from_local(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/base/*.map"),
+            &Some(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/base/main.min.js").to_string()),
+            project_dir,
+            &None,
+            &LinkOptions::default(),
+            false,
+            Some(cache),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_statement_cache() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("v8-statement-cache-{}", std::process::id()));
+        let cache = StatementCache::new(&dir);
+
+        let first = build(&cache, "/p").await?;
+        let files: Vec<PathBuf> = std::fs::read_dir(&dir)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<std::io::Result<_>>()?;
+        assert_eq!(files.len(), first.len());
+
+        // 改掉缓存文件里面的内容，第二次的结果来自缓存才会带上这个标记
+        for f in files.iter() {
+            let mut v: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(f)?)?;
+            v["source_url"] = serde_json::json!("sentinel");
+            std::fs::write(f, v.to_string())?;
+        }
+        let second = build(&cache, "/p").await?;
+        assert_eq!(second.len(), first.len());
+        for (k, a) in first.iter() {
+            let b = &second[k];
+            assert_eq!(b.source_url, "sentinel");
+            assert_eq!(
+                serde_json::to_string(&a.mapping)?,
+                serde_json::to_string(&b.mapping)?
+            );
+        }
+
+        // 参数不同的时候不会用到旧的缓存
+        let third = build(&cache, "/q").await?;
+        assert!(third.values().all(|s| s.source_url != "sentinel"));
+        assert_eq!(std::fs::read_dir(&dir)?.count(), files.len() * 2);
+
+        // 很久没用过的缓存会被清理
+        let old = SystemTime::now() - MAX_AGE - Duration::from_secs(60);
+        std::fs::File::options()
+            .append(true)
+            .open(&files[0])?
+            .set_modified(old)?;
+        assert_eq!(cache.prune().await?, 1);
+        assert!(!files[0].exists());
+        assert_eq!(cache.prune().await?, 0);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_key_sections() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("v8-cache-key-{}", std::process::id()));
+        fs::create_dir_all(&dir).await?;
+        let index = include_str!("../../tests/index/multi.min.js.map");
+        let section = include_str!("../../tests/index/b.min.js.map");
+        let map_file = dir.join("multi.min.js.map").to_string_lossy().to_string();
+        fs::write(&map_file, index).await?;
+
+        let key = |sm: SourceMap| async move {
+            StatementCache::key(&CacheKeyInput {
+                map_content: index,
+                sm: &sm,
+                script_uri: "multi.min.js",
+                script_content: "a;\nb;",
+                project_dir: "/p",
+                source_relocate: &None,
+                link_options: &LinkOptions::default(),
+            })
+            .await
+        };
+        fs::write(dir.join("b.min.js.map"), section).await?;
+        let a = key(decode_source_map(index.as_bytes(), &map_file).await?).await;
+        assert_eq!(
+            a,
+            key(decode_source_map(index.as_bytes(), &map_file).await?).await
+        );
+
+        // index map 本身没变，通过 url 引用的 section 变了
+        fs::write(dir.join("b.min.js.map"), section.replace("EAAC", "EAAE")).await?;
+        let b = key(decode_source_map(index.as_bytes(), &map_file).await?).await;
+        assert_ne!(a, b);
+
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
@@ -1,26 +1,73 @@
-use crate::format::MappingItem;
-use crate::fputil::is_legal_source_path;
+use crate::format::script_coverage::FunctionCoverage;
+use crate::format::{FunctionItem, MappingItem, NameItem};
+use crate::fputil::{is_legal_source_path, is_vendor_source_path, safe_join};
+use crate::pragma::{parse_ignores, IgnoreRegions};
+use crate::statement::Statement;
+use crate::traverse::{parse_code, CodeSpan, ParsedCode};
 use anyhow::{anyhow, Result};
 use sourcemap::SourceMap;
+use std::borrow::Cow;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
-use tracing::instrument;
+use tracing::{debug, instrument, warn};
+
+#[derive(Debug, Clone)]
+pub struct LinkOptions {
+    /// source map 带有 ignoreList 的时候，用它过滤第三方代码，否则按 node_modules 路径过滤
+    pub respect_ignore_list: bool,
+}
+
+impl Default for LinkOptions {
+    fn default() -> Self {
+        LinkOptions {
+            respect_ignore_list: true,
+        }
+    }
+}
+
+pub struct SourceFilter {
+    ignored: HashSet<u32>,
+    use_ignore_list: bool,
+}
+
+impl SourceFilter {
+    pub fn new(source_map: &SourceMap, options: &LinkOptions) -> Self {
+        let ignored: HashSet<u32> = source_map.ignore_list().copied().collect();
+        let use_ignore_list = options.respect_ignore_list && !ignored.is_empty();
+        if use_ignore_list {
+            debug!("使用 ignoreList 过滤 {} 个源文件", ignored.len());
+        }
+        SourceFilter {
+            ignored,
+            use_ignore_list,
+        }
+    }
+
+    pub fn allow(&self, src_id: u32, source: &str) -> bool {
+        if !is_legal_source_path(source) {
+            return false;
+        }
+        if self.use_ignore_list {
+            !self.ignored.contains(&src_id)
+        } else {
+            !is_vendor_source_path(source)
+        }
+    }
+}
 
 #[instrument(skip(source_content, source_map))]
 pub async fn source_map_link<'a>(
     source_content: &'a str,
     source_map: &'a SourceMap,
+    options: &'a LinkOptions,
 ) -> Result<Vec<MappingItem>> {
-    let mut generated_source_sect = vec![0];
-    for s in source_content.split('\n') {
-        let last = generated_source_sect.last().unwrap();
-        generated_source_sect.push(last + s.chars().count() as u32 + 1)
-    }
+    let filter = SourceFilter::new(source_map, options);
+    let generated_source_sect = line_offsets(source_content);
 
     let mut line_length_map: HashMap<&str, Vec<u32>> = HashMap::new();
     for (i, s) in source_map.sources().enumerate() {
-        if !is_legal_source_path(s) {
+        if !filter.allow(i as u32, s) {
             continue;
         }
         line_length_map.insert(
@@ -47,7 +94,7 @@ pub async fn source_map_link<'a>(
             } else {
                 start
             };
-            if !is_legal_source_path(source) {
+            if !filter.allow(x.get_src_id(), source) {
                 continue;
             }
             let m = MappingItem {
@@ -60,6 +107,7 @@ pub async fn source_map_link<'a>(
                 last_original_column: x.get_src_col(),
                 count: 0,
                 idx: i,
+                skip: false,
             };
             sector_map.push(m);
         }
@@ -79,8 +127,9 @@ pub async fn source_map_link<'a>(
             last_original_column: x.get_src_col(),
             count: 0,
             idx: n - 1,
+            skip: false,
         };
-        if is_legal_source_path(source) {
+        if filter.allow(x.get_src_id(), source) {
             sector_map.push(m);
         }
     }
@@ -249,6 +298,217 @@ pub async fn source_map_link<'a>(
         .collect())
 }
 
+/// 每一行起始位置的字符 offset
+pub fn line_offsets(source_content: &str) -> Vec<u32> {
+    let mut sect = vec![0];
+    for s in source_content.split('\n') {
+        let last = sect.last().unwrap();
+        sect.push(last + s.chars().count() as u32 + 1)
+    }
+    sect
+}
+
+/// 收集带有 name 的 token，按生成代码的 offset 排序
+#[instrument(skip_all)]
+pub fn collect_names(
+    source_content: &str,
+    source_map: &SourceMap,
+    options: &LinkOptions,
+) -> Vec<NameItem> {
+    let filter = SourceFilter::new(source_map, options);
+    let sect = line_offsets(source_content);
+    let mut r: Vec<NameItem> = source_map
+        .tokens()
+        .filter(|t| filter.allow(t.get_src_id(), t.get_source().unwrap_or_default()))
+        .filter_map(|t| {
+            let (line, col) = t.get_dst();
+            let start = sect.get(line as usize)? + col;
+            t.get_name().map(|n| NameItem {
+                generated_column: start,
+                name: n.to_string(),
+            })
+        })
+        .collect();
+    r.sort_by_key(|n| n.generated_column);
+    r
+}
+
+/// 解析源码，用来查找函数声明和 ignore 注释
+///
+/// 优先使用 sourcesContent，没有的话从 `code_dir` 下读取源文件
+#[instrument(skip(source_map, options))]
+pub fn parse_original_sources(
+    source_map: &SourceMap,
+    options: &LinkOptions,
+    code_dir: &str,
+) -> (HashMap<String, ParsedCode>, HashMap<String, IgnoreRegions>) {
+    let filter = SourceFilter::new(source_map, options);
+    let mut parsed_map = HashMap::new();
+    let mut ignores_map = HashMap::new();
+    for (i, s) in source_map.sources().enumerate() {
+        if !filter.allow(i as u32, s) || !is_file_extension_allowed(s, &["js", "jsx", "ts", "tsx"])
+        {
+            continue;
+        }
+        let content = match source_map.get_source_contents(i as u32) {
+            Some(c) => Cow::Borrowed(c),
+            None => match safe_join(Path::new(code_dir), s)
+                .and_then(|p| Ok(std::fs::read_to_string(p)?))
+            {
+                Ok(c) => Cow::Owned(c),
+                Err(_) => continue,
+            },
+        };
+        let parsed = match parse_code(&content, s) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                warn!("解析源码失败 {}", e);
+                None
+            }
+        };
+        let ignores = parse_ignores(&content, parsed.as_ref());
+        if !ignores.is_empty() {
+            ignores_map.insert(s.to_string(), ignores);
+        }
+        if let Some(p) = parsed {
+            parsed_map.insert(s.to_string(), p);
+        }
+    }
+    (parsed_map, ignores_map)
+}
+
+/// 标记被 ignore 注释排除的语句，和 nyc 一样保留在报告里面，统计的时候算作 skipped
+pub fn apply_ignores(
+    mut mapping: Vec<MappingItem>,
+    ignores: &HashMap<String, IgnoreRegions>,
+) -> Vec<MappingItem> {
+    for m in mapping.iter_mut() {
+        if let Some(r) = ignores.get(&m.source) {
+            m.skip = r.is_ignored(
+                (m.original_line, m.original_column),
+                (m.last_original_line, m.last_original_column + 1),
+            );
+        }
+    }
+    mapping
+}
+
+/// 函数头部结束的位置（参数列表、函数体或者箭头开始的地方），函数名只会出现在这之前
+fn header_end(code: &[char], start: u32, end: u32) -> u32 {
+    let end = end.min(code.len() as u32);
+    for i in start..end {
+        match code[i as usize] {
+            '(' | '{' => return i,
+            '=' if code.get(i as usize + 1) == Some(&'>') => return i,
+            _ => {}
+        }
+    }
+    end
+}
+
+/// 把 v8 的函数覆盖率映射到源码上
+///
+/// 函数名优先使用 source map 里面函数起始 token 的 name，其次是解析源码得到的声明名称
+#[instrument(skip_all)]
+pub fn function_link(
+    functions: &[FunctionCoverage],
+    code: &[char],
+    statement: &Statement,
+) -> Vec<FunctionItem> {
+    let mut gen_order: Vec<&MappingItem> = statement.mapping.iter().collect();
+    gen_order.sort_by_key(|m| m.generated_column);
+
+    let mut r: Vec<FunctionItem> = vec![];
+    let mut index: HashMap<(String, u32, u32), usize> = HashMap::new();
+    for f in functions {
+        let range = match f.ranges.first() {
+            Some(range) => range,
+            None => continue,
+        };
+        // 整个脚本的顶层函数
+        if range.start_offset == 0 && f.function_name.is_empty() {
+            continue;
+        }
+        let i = gen_order.partition_point(|m| m.generated_column < range.start_offset);
+        let first = match gen_order.get(i) {
+            Some(m) if m.generated_column < range.end_offset => *m,
+            _ => continue,
+        };
+
+        let header = header_end(code, range.start_offset, range.end_offset);
+        let j = statement
+            .names
+            .partition_point(|n| n.generated_column < range.start_offset);
+        let token_name = statement
+            .names
+            .get(j)
+            .filter(|n| n.generated_column < header)
+            .map(|n| n.name.clone());
+
+        let original = statement
+            .parsed
+            .get(&first.source)
+            .and_then(|p| p.function_at(first.original_line, first.original_column));
+        let (loc, decl, parsed_name) = match original {
+            Some(o) => (o.loc.clone(), o.decl.clone(), Some(o.name.clone())),
+            None => {
+                let mut loc = CodeSpan {
+                    start_line: first.original_line,
+                    start_column: first.original_column,
+                    end_line: first.last_original_line,
+                    end_column: first.last_original_column,
+                    ..Default::default()
+                };
+                for m in gen_order[i..]
+                    .iter()
+                    .take_while(|m| m.generated_column < range.end_offset)
+                    .filter(|m| m.source == first.source)
+                {
+                    if (m.original_line, m.original_column) < (loc.start_line, loc.start_column) {
+                        (loc.start_line, loc.start_column) = (m.original_line, m.original_column);
+                    }
+                    if (m.last_original_line, m.last_original_column)
+                        > (loc.end_line, loc.end_column)
+                    {
+                        (loc.end_line, loc.end_column) =
+                            (m.last_original_line, m.last_original_column);
+                    }
+                }
+                (loc.clone(), loc, None)
+            }
+        };
+        let name = token_name.or(parsed_name).unwrap_or_else(|| {
+            if f.function_name.is_empty() {
+                "(anonymous)".to_string()
+            } else {
+                f.function_name.clone()
+            }
+        });
+
+        let skip = statement
+            .ignores
+            .get(&first.source)
+            .is_some_and(|r| r.is_span_ignored(&loc));
+
+        let key = (first.source.clone(), loc.start_line, loc.start_column);
+        match index.get(&key) {
+            Some(&k) => r[k].count += range.count,
+            None => {
+                index.insert(key, r.len());
+                r.push(FunctionItem {
+                    source: first.source.clone(),
+                    name,
+                    decl,
+                    loc,
+                    count: range.count,
+                    skip,
+                });
+            }
+        }
+    }
+    r
+}
+
 fn is_file_extension_allowed<P: AsRef<Path>>(path: P, file_extensions: &[&str]) -> bool {
     let ext = path
         .as_ref()
@@ -271,7 +531,12 @@ mod test {
         >(include_str!("../tests/base/v8-coverage.json"))
         .map_err(|e| anyhow!("parse script coverage error: {}", e))?;
         let source_map = SourceMap::from_slice(include_bytes!("../tests/base/main.min.js.map"))?;
-        let r = source_map_link(&script_coverage[0].source, &source_map).await?;
+        let r = source_map_link(
+            &script_coverage[0].source,
+            &source_map,
+            &LinkOptions::default(),
+        )
+        .await?;
         // tokio::fs::write(
         //     "tests/base/source_map_link.json",
         //     serde_json::to_string_pretty(&r)?,
@@ -291,7 +556,12 @@ mod test {
         .map_err(|e| anyhow!("parse script coverage error: {}", e))?;
         let source_map =
             SourceMap::from_slice(include_bytes!("../tests/jsx/main.f272a57c.chunk.js.map"))?;
-        let r = source_map_link(&script_coverage[0].source, &source_map).await?;
+        let r = source_map_link(
+            &script_coverage[0].source,
+            &source_map,
+            &LinkOptions::default(),
+        )
+        .await?;
 
         // tokio::fs::write(
         //     "tests/jsx/source_map_link.json",
@@ -305,6 +575,116 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_source_map_link_ignore_list() -> Result<()> {
+        let script_coverage = serde_json::from_str::<
+            Vec<crate::format::script_coverage::ScriptCoverage>,
+        >(include_str!("../tests/jsx/v8-coverage.json"))?;
+        let mut source_map =
+            SourceMap::from_slice(include_bytes!("../tests/jsx/main.f272a57c.chunk.js.map"))?;
+        // ./src/TodoList/model.js
+        source_map.add_to_ignore_list(2);
+        let source = &script_coverage[0].source;
+
+        let r = source_map_link(source, &source_map, &LinkOptions::default()).await?;
+        assert!(!r.is_empty());
+        assert!(r.iter().all(|m| m.source != "./src/TodoList/model.js"));
+
+        let r = source_map_link(
+            source,
+            &source_map,
+            &LinkOptions {
+                respect_ignore_list: false,
+            },
+        )
+        .await?;
+        assert!(r.iter().any(|m| m.source == "./src/TodoList/model.js"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_function_link() -> Result<()> {
+        let script_coverage = serde_json::from_str::<
+            Vec<crate::format::script_coverage::ScriptCoverage>,
+        >(include_str!("../tests/base/v8-coverage.json"))?;
+        let source_map = SourceMap::from_slice(include_bytes!("../tests/base/main.min.js.map"))?;
+        let sc = &script_coverage[0];
+        let options = LinkOptions::default();
+        let statement = Statement {
+            source_url: sc.url.clone(),
+            code_dir: "/".to_string(),
+            mapping: source_map_link(&sc.source, &source_map, &options).await?,
+            names: collect_names(&sc.source, &source_map, &options),
+            parsed: parse_original_sources(&source_map, &options, "/").0,
+            ignores: HashMap::new(),
+            code: sc.source.clone(),
+            sources_content: HashMap::new(),
+        };
+        let code: Vec<char> = sc.source.chars().collect();
+        let fns = function_link(&sc.functions, &code, &statement);
+
+        // 压缩后的 `f` 和 `o` 还原成源码里面的 `f1` 和 `f3`
+        let f1 = fns.iter().find(|f| f.name == "f1").unwrap();
+        assert_eq!(f1.count, 1);
+        assert_eq!(f1.loc.start_line, 3);
+        let f3 = fns.iter().find(|f| f.name == "f3").unwrap();
+        assert_eq!(f3.count, 4);
+        let f2 = fns.iter().find(|f| f.name == "f2").unwrap();
+        assert_eq!(f2.count, 0);
+        assert!(fns.iter().all(|f| f.name != "f" && f.name != "o"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_ignores() -> Result<()> {
+        let mut raw: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/base/main.min.js.map"))?;
+        let content = raw["sourcesContent"][0].as_str().unwrap().replace(
+            "  function f1() {",
+            "  /* istanbul ignore next */\n  function f1() {",
+        );
+        raw["sourcesContent"][0] = serde_json::Value::String(content);
+        let source_map = SourceMap::from_slice(raw.to_string().as_bytes())?;
+        let (_, ignores) = parse_original_sources(&source_map, &LinkOptions::default(), "/");
+        assert_eq!(ignores.len(), 1);
+        let r = ignores.get("src/main.js").unwrap();
+        // 注释插入之后 f1 在第 5 行到第 7 行
+        assert!(r.is_ignored((4, 2), (6, 3)));
+        assert!(!r.is_ignored((1, 2), (1, 28)));
+
+        let mapping = vec![
+            MappingItem {
+                source: "src/main.js".to_string(),
+                generated_column: 0,
+                last_generated_column: 1,
+                original_line: 5,
+                original_column: 4,
+                last_original_line: 5,
+                last_original_column: 20,
+                count: 0,
+                idx: 0,
+                skip: false,
+            },
+            MappingItem {
+                source: "src/main.js".to_string(),
+                generated_column: 2,
+                last_generated_column: 3,
+                original_line: 1,
+                original_column: 2,
+                last_original_line: 1,
+                last_original_column: 20,
+                count: 0,
+                idx: 1,
+                skip: false,
+            },
+        ];
+        let r = apply_ignores(mapping, &ignores);
+        assert_eq!(r.len(), 2);
+        assert!(r[0].skip);
+        assert!(!r[1].skip);
+        Ok(())
+    }
+
     #[test]
     fn test_str_len() {
         let s = "1234567890";
@@ -1,96 +1,313 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
 use swc_core::common::sync::Lrc;
-use swc_core::common::{
-    errors::{ColorConfig, Handler},
-    FileName, SourceMap, Spanned,
+use swc_core::common::{BytePos, FileName, SourceFile, SourceMap, Span, Spanned};
+use swc_core::ecma::ast::{
+    ArrowExpr, BlockStmtOrExpr, ClassMethod, Constructor, Decl, Expr, FnDecl, FnExpr, IfStmt,
+    MethodProp, Pat, PrivateMethod, PropName, Stmt, VarDeclarator,
 };
-use swc_core::ecma::ast::{FnDecl, FnExpr, Function, Module};
-use swc_core::ecma::parser::{lexer::Lexer, Capturing, Parser, StringInput, Syntax};
+use swc_core::ecma::parser::{lexer::Lexer, EsSyntax, Parser, StringInput, Syntax, TsSyntax};
 use swc_core::ecma::visit::{Visit, VisitWith};
 
-fn parse() {
-    let cm: Lrc<SourceMap> = Default::default();
-    let handler = Handler::with_tty_emitter(ColorConfig::Auto, true, false, Some(cm.clone()));
+/// 代码中的一段区间，offset 按字符计算，和 v8 覆盖率的 offset 一致
+/// line 从 0 开始，column 从 0 开始
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CodeSpan {
+    pub start: u32,
+    pub end: u32,
+    pub start_line: u32,
+    pub start_column: u32,
+    pub end_line: u32,
+    pub end_column: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionSpan {
+    pub name: String,
+    pub decl: CodeSpan,
+    pub loc: CodeSpan,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IfSpan {
+    pub loc: CodeSpan,
+    pub cons: CodeSpan,
+    pub alt: Option<CodeSpan>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ParsedCode {
+    pub statements: Vec<CodeSpan>,
+    pub functions: Vec<FunctionSpan>,
+    pub ifs: Vec<IfSpan>,
+}
 
-    // Real usage
-    // let fm = cm
-    //     .load_file(Path::new("test.js"))
-    //     .expect("failed to load test.js");
+impl ParsedCode {
+    /// 包含 `(line, column)` 位置的最内层函数
+    pub fn function_at(&self, line: u32, column: u32) -> Option<&FunctionSpan> {
+        self.functions
+            .iter()
+            .filter(|f| {
+                (f.loc.start_line, f.loc.start_column) <= (line, column)
+                    && (line, column) <= (f.loc.end_line, f.loc.end_column)
+            })
+            .min_by_key(|f| f.loc.end - f.loc.start)
+    }
+}
 
+fn syntax_of(filename: &str) -> Syntax {
+    match Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+    {
+        "ts" | "mts" | "cts" => Syntax::Typescript(TsSyntax::default()),
+        "tsx" => Syntax::Typescript(TsSyntax {
+            tsx: true,
+            ..Default::default()
+        }),
+        _ => Syntax::Es(EsSyntax {
+            jsx: true,
+            ..Default::default()
+        }),
+    }
+}
+
+/// 解析 js/ts 代码，收集语句和函数的位置
+pub fn parse_code(code: &str, filename: &str) -> Result<ParsedCode> {
+    let cm: Lrc<SourceMap> = Default::default();
     let fm = cm.new_source_file(
-        FileName::Custom("test.js".into()).into(),
-        r#"function foo() {
-            function bar() {
-            }
-        }"#
-        .into(),
+        FileName::Custom(filename.to_string()).into(),
+        code.to_string(),
     );
 
     let lexer = Lexer::new(
-        Syntax::Es(Default::default()),
+        syntax_of(filename),
         Default::default(),
         StringInput::from(&*fm),
         None,
     );
+    let mut parser = Parser::new_from(lexer);
+    let program = parser
+        .parse_program()
+        .map_err(|e| anyhow!("解析代码失败 {}: {}", filename, e.kind().msg()))?;
 
-    let capturing = Capturing::new(lexer);
+    let mut line_sect = vec![0];
+    for s in code.split('\n') {
+        let last = line_sect.last().unwrap();
+        line_sect.push(last + s.chars().count() as u32 + 1)
+    }
+    let mut collector = Collector {
+        cm: cm.clone(),
+        fm,
+        line_sect,
+        result: ParsedCode::default(),
+        anonymous: 0,
+        pending_name: None,
+    };
+    program.visit_with(&mut collector);
+    Ok(collector.result)
+}
 
-    let mut parser = Parser::new_from(capturing);
+struct Collector {
+    cm: Lrc<SourceMap>,
+    fm: Lrc<SourceFile>,
+    line_sect: Vec<u32>,
+    result: ParsedCode,
+    anonymous: usize,
+    // 变量声明 `const a = function () {}` 里面的变量名，用来给匿名函数命名
+    pending_name: Option<String>,
+}
 
-    for e in parser.take_errors() {
-        e.into_diagnostic(&handler).emit();
+impl Collector {
+    fn position(&self, pos: BytePos) -> (u32, u32, u32) {
+        let loc = self.cm.lookup_char_pos_with(self.fm.clone(), pos);
+        let line = loc.line as u32 - 1;
+        let column = loc.col.0 as u32;
+        (self.line_sect[line as usize] + column, line, column)
     }
 
-    let module = parser
-        .parse_module()
-        .map_err(|e| e.into_diagnostic(&handler).emit())
-        .expect("Failed to parse module.");
+    fn code_span(&self, span: Span) -> CodeSpan {
+        let (start, start_line, start_column) = self.position(span.lo);
+        let (end, end_line, end_column) = self.position(span.hi);
+        CodeSpan {
+            start,
+            end,
+            start_line,
+            start_column,
+            end_line,
+            // istanbul 的 end column 指向最后一个字符之后
+            end_column,
+        }
+    }
 
-    Shower { handler: &handler }.visit_module(&module);
+    fn name_or_anonymous(&mut self, name: Option<String>) -> String {
+        match name.or_else(|| self.pending_name.take()) {
+            Some(n) => n,
+            None => {
+                let n = format!("(anonymous_{})", self.anonymous);
+                self.anonymous += 1;
+                n
+            }
+        }
+    }
 
-    dbg!(parser.input().take());
+    fn add_function(&mut self, name: String, decl: Span, loc: Span) {
+        let f = FunctionSpan {
+            name,
+            decl: self.code_span(decl),
+            loc: self.code_span(loc),
+        };
+        self.result.functions.push(f);
+    }
 }
-struct Shower<'a> {
-    handler: &'a Handler,
+
+fn prop_name(key: &PropName) -> Option<String> {
+    match key {
+        PropName::Ident(i) => Some(i.sym.to_string()),
+        PropName::Str(s) => Some(s.value.to_string()),
+        PropName::Num(n) => Some(n.value.to_string()),
+        _ => None,
+    }
 }
 
-impl Shower<'_> {
-    fn show(&self, name: &str, node: &dyn Spanned) {
-        let span = node.span();
-        dbg!(&name, &span);
+impl Visit for Collector {
+    fn visit_stmt(&mut self, n: &Stmt) {
+        match n {
+            Stmt::Block(_)
+            | Stmt::Empty(_)
+            | Stmt::Decl(Decl::Fn(_))
+            | Stmt::Decl(Decl::Class(_)) => {}
+            _ => {
+                let s = self.code_span(n.span());
+                self.result.statements.push(s);
+            }
+        }
+        n.visit_children_with(self)
+    }
 
-        // self.handler.struct_span_err(span, name).emit();
+    fn visit_if_stmt(&mut self, n: &IfStmt) {
+        let i = IfSpan {
+            loc: self.code_span(n.span),
+            cons: self.code_span(n.cons.span()),
+            alt: n.alt.as_ref().map(|alt| self.code_span(alt.span())),
+        };
+        self.result.ifs.push(i);
+        n.visit_children_with(self)
+    }
+
+    fn visit_var_declarator(&mut self, n: &VarDeclarator) {
+        n.name.visit_with(self);
+        if let (Pat::Ident(ident), Some(init)) = (&n.name, &n.init) {
+            if matches!(**init, Expr::Fn(_) | Expr::Arrow(_)) {
+                self.pending_name = Some(ident.id.sym.to_string());
+            }
+        }
+        n.init.visit_with(self);
+        self.pending_name = None;
     }
-}
 
-impl Visit for Shower<'_> {
     fn visit_fn_decl(&mut self, n: &FnDecl) {
-        dbg!(&n);
-        self.show("FnDecl", n);
+        self.pending_name = None;
+        self.add_function(n.ident.sym.to_string(), n.ident.span, n.function.span);
         n.visit_children_with(self)
     }
+
     fn visit_fn_expr(&mut self, n: &FnExpr) {
-        dbg!(&n);
-        self.show("FnExpr", n);
+        let name = self.name_or_anonymous(n.ident.as_ref().map(|i| i.sym.to_string()));
+        let decl = match &n.ident {
+            Some(i) => i.span,
+            None => n.function.span,
+        };
+        self.add_function(name, decl, n.function.span);
+        n.visit_children_with(self)
+    }
+
+    fn visit_arrow_expr(&mut self, n: &ArrowExpr) {
+        let name = self.name_or_anonymous(None);
+        self.add_function(name, n.span, n.span);
+        if let BlockStmtOrExpr::Expr(e) = &*n.body {
+            // 箭头函数的表达式体也算一个语句
+            let s = self.code_span(e.span());
+            self.result.statements.push(s);
+        }
+        n.visit_children_with(self)
+    }
+
+    fn visit_class_method(&mut self, n: &ClassMethod) {
+        self.pending_name = None;
+        let name = self.name_or_anonymous(prop_name(&n.key));
+        self.add_function(name, n.key.span(), n.function.span);
         n.visit_children_with(self)
     }
 
-    fn visit_function(&mut self, n: &Function) {
-        dbg!(&n);
-        self.show("Function", n);
+    fn visit_private_method(&mut self, n: &PrivateMethod) {
+        self.pending_name = None;
+        self.add_function(format!("#{}", n.key.name), n.key.span, n.function.span);
         n.visit_children_with(self)
     }
 
-    fn visit_module(&mut self, n: &Module) {
-        self.show("Module", n);
+    fn visit_constructor(&mut self, n: &Constructor) {
+        self.pending_name = None;
+        self.add_function("constructor".to_string(), n.key.span(), n.span);
+        n.visit_children_with(self)
+    }
+
+    fn visit_method_prop(&mut self, n: &MethodProp) {
+        self.pending_name = None;
+        let name = self.name_or_anonymous(prop_name(&n.key));
+        self.add_function(name, n.key.span(), n.function.span);
         n.visit_children_with(self)
     }
 }
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     #[test]
-    fn test_parse() {
-        parse();
+    fn test_parse() -> Result<()> {
+        let parsed = parse_code(
+            r#"function foo() {
+            function bar() {
+            }
+        }"#,
+            "test.js",
+        )?;
+        let names: Vec<&str> = parsed.functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["foo", "bar"]);
+        assert_eq!(parsed.functions[1].loc.start_line, 1);
+        assert_eq!(parsed.functions[1].loc.start_column, 12);
+        let (outer, inner) = (&parsed.functions[0].loc, &parsed.functions[1].loc);
+        assert!(outer.start <= inner.start && inner.end <= outer.end);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_names() -> Result<()> {
+        let parsed = parse_code(include_str!("../tests/base/src/main.js"), "main.js")?;
+        let names: Vec<&str> = parsed.functions.iter().map(|f| f.name.as_str()).collect();
+        assert!(names.contains(&"f1"));
+        assert!(names.contains(&"f2"));
+        assert!(names.contains(&"f3"));
+        assert!(!parsed.statements.is_empty());
+
+        let f1 = parsed.function_at(4, 4).unwrap();
+        assert_eq!(f1.name, "f1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_char_offset() -> Result<()> {
+        let code = "let a = '中文';\nfunction b() {}";
+        let parsed = parse_code(code, "a.js")?;
+        let b = &parsed.functions[0];
+        assert_eq!(
+            b.loc.start,
+            code.split('\n').next().unwrap().chars().count() as u32 + 1
+        );
+        assert_eq!(b.loc.start_line, 1);
+        Ok(())
     }
 }
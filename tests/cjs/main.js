@@ -0,0 +1,11 @@
+const rand = Math.random()
+
+function f1() {
+  return 'f1'
+}
+
+function f2() {
+  return 'f2'
+}
+
+module.exports = { f1, f2, v: f1() }